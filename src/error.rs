@@ -0,0 +1,129 @@
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+/// Error returned when the init function cannot be generated.
+#[derive(Debug)]
+pub enum InitError {
+    /// A file or directory could not be read or written.
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// A path is not valid UTF-8, so it cannot be turned into a module path.
+    InvalidPath(PathBuf),
+    /// A source file could not be parsed. The location of the error is available through [`syn::Error::span`].
+    Parse {
+        path: PathBuf,
+        error: syn::Error,
+    },
+    /// The JSON file set with [`InitOptions::sidecar`](crate::InitOptions::sidecar) is malformed.
+    Json {
+        path: PathBuf,
+        error: serde_json::Error,
+    },
+    /// An `#[init]` attribute is malformed.
+    InvalidAttribute {
+        path: PathBuf,
+        function: String,
+        message: String,
+    },
+    /// Two init functions are called through the same path.
+    DuplicateFunction {
+        call: String,
+        files: Vec<PathBuf>,
+    },
+    /// Init functions depend on each other through `#[init(after = "...")]` and `#[init(before = "...")]`.
+    /// The paths are listed in the order they would have to run, the first one is repeated at the end.
+    DependencyCycle(Vec<String>),
+    /// Functions filling the same slot with `#[init(slot = "...")]` are not excluded from each other by `#[cfg]` attributes.
+    SlotConflict {
+        slot: String,
+        calls: Vec<String>,
+    },
+    /// A function cannot be called in the code generated with the selected options.
+    Incompatible {
+        call: String,
+        message: String,
+    },
+    /// [`InitOptions::order_override`](crate::InitOptions::order_override) does not match the discovered functions.
+    OrderOverride {
+        /// Listed paths that do not belong to any discovered function.
+        unknown: Vec<String>,
+        /// Discovered functions that are not listed.
+        missing: Vec<String>,
+    },
+    /// Init functions, with the files defining them, that are not reachable from the module tree.
+    /// See [`InitOptions::strict_module_tree`](crate::InitOptions::strict_module_tree).
+    Unreachable(Vec<(String, PathBuf)>),
+    /// Modules, with the files declaring them, that contain no init functions.
+    /// See [`InitOptions::require_init_per_module`](crate::InitOptions::require_init_per_module).
+    EmptyModules(Vec<(String, PathBuf)>),
+    /// The selected options cannot be used together.
+    InvalidOptions(String),
+    /// `OUT_DIR` is not set and [`InitOptions::out_dir`](crate::InitOptions::out_dir) is not given, so there is nowhere to write the generated code.
+    MissingOutDir,
+}
+
+impl Display for InitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InitError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            InitError::InvalidPath(path) => write!(f, "{}: path is not valid UTF-8", path.display()),
+            InitError::Parse { path, error } => {
+                let start = error.span().start();
+                // Errors without a location, like those created with `Span::call_site`, have line 0.
+                if start.line > 0 {
+                    write!(f, "{}:{}:{}: failed to parse file: {}", path.display(), start.line, start.column + 1, error)
+                } else {
+                    write!(f, "{}: failed to parse file: {}", path.display(), error)
+                }
+            }
+            InitError::Json { path, error } => write!(f, "{}: failed to parse JSON: {}", path.display(), error),
+            InitError::InvalidAttribute { path, function, message } => write!(f, "{}: fn {}: {}", path.display(), function, message),
+            InitError::DuplicateFunction { call, files } => {
+                let files: Vec<String> = files.iter().map(|file| file.display().to_string()).collect();
+                write!(f, "{} is called more than once (defined in {})", call, files.join(", "))
+            }
+            InitError::DependencyCycle(cycle) => write!(f, "init functions depend on each other: {}", cycle.join(" -> ")),
+            InitError::SlotConflict { slot, calls } => {
+                write!(f, "slot {} is filled by functions that can be enabled at once: {}", slot, calls.join(", "))
+            }
+            InitError::Incompatible { call, message } => write!(f, "{}: {}", call, message),
+            InitError::OrderOverride { unknown, missing } => {
+                write!(f, "order override does not match the init functions")?;
+                if !unknown.is_empty() {
+                    write!(f, "; unknown: {}", unknown.join(", "))?;
+                }
+                if !missing.is_empty() {
+                    write!(f, "; missing: {}", missing.join(", "))?;
+                }
+                Ok(())
+            }
+            InitError::Unreachable(functions) => {
+                let functions: Vec<String> = functions.iter()
+                    .map(|(call, file)| format!("{} ({})", call, file.display()))
+                    .collect();
+                write!(f, "annotated but not reachable from the module tree: {}", functions.join(", "))
+            }
+            InitError::EmptyModules(modules) => {
+                let modules: Vec<String> = modules.iter()
+                    .map(|(module, file)| format!("{} ({})", module, file.display()))
+                    .collect();
+                write!(f, "modules without init functions: {}", modules.join(", "))
+            }
+            InitError::InvalidOptions(message) => write!(f, "invalid options: {}", message),
+            InitError::MissingOutDir => write!(f, "OUT_DIR is not set. Set InitOptions::out_dir, or use render_init outside of build scripts."),
+        }
+    }
+}
+
+impl std::error::Error for InitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InitError::Io { error, .. } => Some(error),
+            InitError::Parse { error, .. } => Some(error),
+            InitError::Json { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use crate::{InitError, InitOptions};
+
+/// Builder writing an init function to `OUT_DIR`, e.g. when a build script generates several differently named entry points:
+/// ```ignore
+/// rs_init::Generator::new()
+///     .attribute("plugin_init")
+///     .output_file("plugins.rs")
+///     .function_name("init_plugins")
+///     .run()
+///     .unwrap();
+/// ```
+/// The other options are taken from [`Generator::options`], the setters override the matching fields.
+#[derive(Clone, Debug)]
+pub struct Generator {
+    source_dir: String,
+    options: InitOptions,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Generator {
+            source_dir: "src".to_string(),
+            options: InitOptions::default(),
+        }
+    }
+}
+
+impl Generator {
+    /// Creates a generator scanning `src` with the default options.
+    pub fn new() -> Self {
+        Generator::default()
+    }
+
+    /// Sets the scanned directory, `src` by default.
+    pub fn source_dir(mut self, source_dir: impl Into<String>) -> Self {
+        self.source_dir = source_dir.into();
+        self
+    }
+
+    /// Replaces all options. Call it before the other setters, as it overrides their fields.
+    pub fn options(mut self, options: InitOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sets [`InitOptions::output_file`].
+    pub fn output_file(mut self, output_file: impl Into<String>) -> Self {
+        self.options.output_file = output_file.into();
+        self
+    }
+
+    /// Sets [`InitOptions::function_name`].
+    pub fn function_name(mut self, function_name: impl Into<String>) -> Self {
+        self.options.function_name = function_name.into();
+        self
+    }
+
+    /// Sets [`InitOptions::attribute_name`].
+    pub fn attribute(mut self, attribute_name: impl Into<String>) -> Self {
+        self.options.attribute_name = attribute_name.into();
+        self
+    }
+
+    /// Sets [`InitOptions::stage_names`], e.g. `.stages(["logging", "database", "http"])`.
+    pub fn stages<S: Into<String>>(mut self, names: impl IntoIterator<Item = S>) -> Self {
+        self.options.stage_names = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Scans the source directory and writes the generated code, like [`generate_init_function_with`](crate::generate_init_function_with).
+    /// Unlike it, errors are returned instead of panicking.
+    pub fn run(&self) -> Result<(), InitError> {
+        crate::write_init_function(&self.source_dir, &self.options)
+    }
+}
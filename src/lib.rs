@@ -21,7 +21,7 @@
 //!
 //! Build.rs:
 //!
-//! ```rust
+//! ```ignore
 //! fn main() {
 //!     rs_init::default_setup();
 //! }
@@ -30,113 +30,3303 @@
 //! `#[init]` macro can be used on function in any module, but the `rs-init` crate must be able to find the module.
 //! This can be done by adding `pub(crate)` to the module declaration.
 //!
+//! Functions with the same stage are called in the order of their module path and name, so the generated code does not
+//! depend on the file system, see [`InitOptions::tie_break`] for other orders.
+//!
+//! The stage can depend on a feature with `#[init(stage = if cfg!(feature = "fast") { 0 } else { 5 })]`.
+//! The call is then generated once for every branch, gated with the matching `#[cfg]`, so the stage is selected at
+//! compile time and there is no runtime cost. `cfg!` is a constant, so computing the stage at runtime and sorting the
+//! functions in `generated_init` would give the same order, but it would need a sort on every start and could not be
+//! used by the generated constants, like the `GENERATED_INIT_METADATA` of [`InitOptions::metadata`].
+//!
+//! Functions can also be ordered relative to each other with `#[init(stage = 1, after = "crate::db::init")]` and
+//! `before = "..."`, or several paths like `after = ["crate::db::init", "crate::config::init"]`. A function is only moved
+//! behind its dependencies when the stages do not already order them, and a cycle of dependencies fails the build.
+//! Functions of a concurrent stage that depend on each other are not run at the same time, the stage is split into
+//! batches running one after another instead.
+//!
+//! Teardown functions marked with `#[deinit(stage = N)]` are called by the generated `generated_shutdown()` in reverse
+//! stage order, so that the resources set up first are released last. Like `#[init]`, the attribute has to be
+//! provided by a macro crate. Both names follow the configured attribute and function names, see
+//! [`InitOptions::attribute_name`] and [`InitOptions::function_name`].
+//!
 //! You probably would not use this crate by itself, but rather to create some sort of framework and other macros that use it.
 use std::str::FromStr;
 use syn::Item;
-use std::io::Write;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+mod error;
+mod generator;
+mod plan;
+mod step;
+
+pub use error::InitError;
+/// Shorter name of [`InitError`], returned by the `try_` functions like [`try_generate_init_function`].
+pub type Error = InitError;
+pub use generator::Generator;
+pub use plan::{InitPlan, InitPlanEntry};
+pub use step::InitStep;
+
+#[cfg(test)]
+mod tests;
+
+/// Name of the environment variable holding the list of changed files for incremental generation.
+const CHANGED_FILES_VAR: &str = "RS_INIT_CHANGED";
+
+/// Version of the cache format. Caches with a different version are ignored.
+const CACHE_VERSION: u32 = 21;
+
+/// Keys recognized in the `#[init]` attribute. Used to suggest corrections for misspelled keys.
+const KNOWN_KEYS: &[&str] = &["stage", "priority", "group", "before", "after", "profile", "retries", "guard", "cleanup", "call", "description", "first", "timeout_ms", "slot"];
+
+/// How the functions of a stage are run, see [`InitOptions::stage_strategy`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// The functions are called one after another.
+    Sequential,
+    /// Every function is called on its own thread with `std::thread::scope`, the next stage starts after all threads have finished.
+    Threaded,
+    /// The functions are run concurrently with `futures::future::join_all`, like with [`InitOptions::join_stages`].
+    Async,
+    /// Like [`Strategy::Async`], but in fallible mode all functions of the stage run to completion and their errors are
+    /// combined with [`InitOptions::error_aggregator`] instead of returning the first one.
+    AsyncAggregate,
+}
+
+/// Order of the functions within a stage, see [`InitOptions::tie_break`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TieBreak {
+    /// The functions are sorted by the path of their module and then by their name. This is the default, it only
+    /// depends on the functions themselves, so the generated code is the same on every machine.
+    Module,
+    /// The functions keep the order in which they were found. Files found by walking the directory are ordered by path,
+    /// files included with `include!` are ordered like the file including them and functions within a file by their
+    /// position, so the order is the same on every machine and with `RS_INIT_CHANGED`.
+    Discovery,
+    /// The functions are sorted by path.
+    Path,
+    /// The functions are sorted by a stable hash of their path, spreading related modules over the stage
+    /// while staying the same in every build.
+    Hash,
+}
+
+/// Function rewriting the path of every init function, see [`InitOptions::path_transform`].
+#[derive(Clone)]
+pub struct PathTransform(std::sync::Arc<dyn Fn(&str) -> String + Send + Sync>);
 
+impl PathTransform {
+    /// Wraps a function taking a path like `crate::db::init` and returning the path to call instead.
+    pub fn new(transform: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+        PathTransform(std::sync::Arc::new(transform))
+    }
+}
+
+impl std::fmt::Debug for PathTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PathTransform")
+    }
+}
+
+/// Options controlling the code generated by [`generate_init_function_with`] and [`render_init_with`].
+#[derive(Clone, Debug)]
+pub struct InitOptions {
+    /// Generates `generated_init(stages: &[i64])`, which runs only the functions whose stage is in `stages`.
+    /// The functions are still called in stage order, regardless of the order of `stages`.
+    pub select_stages: bool,
+    /// Rewrites the paths of the generated calls, e.g. to call a function through a public re-export when its definition module is not reachable.
+    /// Each entry is a `(source, target)` pair of paths. If a call path is equal to `source` or starts with `source::`, that part is replaced by `target`.
+    /// When several sources match, the longest one is used.
+    pub path_rewrites: Vec<(String, String)>,
+    /// Writes the calls of each stage into a separate file `init_stage_{n}.rs` defining `generated_init_stage_{n}()`,
+    /// so that the stages can be included independently. `init.rs` includes all of them and defines `generated_init`, which calls each stage function in order.
+    /// Negative stages are written with an `m` prefix, e.g. `init_stage_m1.rs` and `generated_init_stage_m1()`.
+    pub split_by_stage: bool,
+    /// Calls higher stages first. Functions with the same stage keep their relative order.
+    pub descending: bool,
+    /// Generates `pub async fn generated_init()`, which awaits the `async` init functions.
+    /// Synchronous init functions are called as usual. Without it and without [`InitOptions::split_async`], async init
+    /// functions are rejected, as the futures they return would never run.
+    pub asynchronous: bool,
+    /// In asynchronous mode, runs the functions of a stage concurrently with `futures::future::join_all`.
+    /// The next stage starts only after all functions of the previous stage have finished.
+    /// The generated code uses the `futures` crate, so it must be a dependency of the crate including it.
+    pub join_stages: bool,
+    /// Adds the time of generation to the header comment of the generated files.
+    /// It is disabled by default, because it makes the output differ between otherwise identical builds.
+    pub timestamp: bool,
+    /// Additionally generates `generated_init_registry() -> Vec<Box<dyn rs_init::InitStep>>`, returning every init function wrapped in an [`InitStep`].
+    /// The generated code refers to `rs_init::InitStep`, so `rs-init` must also be a regular dependency of the crate including it.
+    /// Async init functions cannot be used in the registry.
+    pub registry: bool,
+    /// Indentation used for each level of the generated code. Defaults to a tab.
+    pub indent: String,
+    /// Calls all functions of a module before the functions of the next module, in stage order within each module.
+    /// Modules are ordered by their path. This cannot be combined with [`InitOptions::split_by_stage`].
+    pub group_by_module_first: bool,
+    /// Omits functions with a stage lower than this, e.g. bootstrap stages already performed by a test harness.
+    pub min_stage: Option<i64>,
+    /// Omits functions with a stage higher than this. Together with [`InitOptions::min_stage`] it selects a window of stages.
+    pub max_stage: Option<i64>,
+    /// Generates both the synchronous `generated_init()` and the asynchronous `generated_init_async()`, e.g. during a migration to async.
+    /// All init functions must be synchronous, because they are called from both. This cannot be combined with [`InitOptions::split_by_stage`].
+    pub sync_and_async: bool,
+    /// Generates the synchronous `generated_init()` calling only the synchronous init functions, and the asynchronous
+    /// `generated_init_async()` awaiting only the async ones, both in stage order. This cannot be combined with
+    /// [`InitOptions::asynchronous`], [`InitOptions::sync_and_async`] or [`InitOptions::split_by_stage`], nor with
+    /// [`InitOptions::stage_names`] or [`InitOptions::timed`], whose functions would call async functions synchronously.
+    pub split_async: bool,
+    /// Paths of all init functions in the order they should be called, e.g. `crate::db::init`.
+    /// If it is not empty, it fully determines the order and stages are ignored for ordering.
+    /// Every discovered function must be listed and every listed function must be discovered.
+    /// This cannot be combined with [`InitOptions::split_by_stage`].
+    pub order_override: Vec<String>,
+    /// Reports every discovered init function and every parsed file that contains no init functions.
+    /// In build scripts the messages are printed as `cargo:warning`, otherwise they are written to stderr.
+    pub verbose: bool,
+    /// Generates `generated_init() -> Result<(), E>`, where `E` is [`InitOptions::error_type`].
+    /// Init functions returning a `Result` are called with `?`, so the first error is returned and the remaining functions are not called.
+    /// Their error types must be convertible into `E`. Other init functions are called as usual.
+    /// In asynchronous mode the calls are awaited first, e.g. `crate::db::init().await?;`.
+    /// Functions marked with `#[init(stage = 0, retries = 3)]` are called again up to 3 times before their last error is returned.
+    /// Async functions marked with `#[init(stage = 0, timeout_ms = 5000)]` are awaited with `tokio::time::timeout`, and
+    /// `tokio::time::error::Elapsed` is returned if they take longer, so it must be convertible into `E` as well.
+    pub fallible: bool,
+    /// Enables [`InitOptions::fallible`] if any init function returns a `Result`, so that its error is returned by
+    /// `generated_init` instead of being ignored. Without such functions `generated_init` returns nothing as usual.
+    pub auto_fallible: bool,
+    /// Error type returned by the generated functions in [`InitOptions::fallible`] mode. Defaults to `Box<dyn std::error::Error>`.
+    pub error_type: String,
+    /// Macro used to log every init call, e.g. `log::info` or `tracing::info`. Disabled by default.
+    /// The generated code calls it as `log::info!("starting crate::db::init");`, so the crate providing it has to be a dependency.
+    pub log_macro: Option<String>,
+    /// Message logged before each call. `{path}` is replaced with the path of the init function,
+    /// `{description}` with the description set with `#[init(description = "...")]`, or the path if there is none.
+    pub log_start_format: String,
+    /// Message logged after each call, with the same placeholders as [`InitOptions::log_start_format`].
+    pub log_finish_format: String,
+    /// Alternative names for the `#[init]` attribute keys, as `(alias, key)` pairs.
+    /// With `("order", "stage")`, `#[init(order = 1)]` is the same as `#[init(stage = 1)]`.
+    pub key_aliases: Vec<(String, String)>,
+    /// Emits `compile_error!` instead of an empty `generated_init` when no init functions are found,
+    /// so the problem is reported where the generated file is included.
+    pub error_if_empty: bool,
+    /// Order of the groups set with `#[init(stage = 0, group = "core")]`. If it is not empty, all functions of a group run
+    /// before the functions of the next group, and stages only order the functions within a group.
+    /// Groups that are not listed run afterwards in name order, functions without a group run last.
+    /// This cannot be combined with [`InitOptions::split_by_stage`] or [`InitOptions::order_override`].
+    pub group_order: Vec<String>,
+    /// Skips `lib.rs` and `main.rs` in the source directory, while the other files and subdirectories are still scanned.
+    /// With [`InitOptions::follow_module_tree`] the modules declared in them are still followed.
+    pub skip_root: bool,
+    /// Path replacing `crate` at the start of the generated calls, applied after [`InitOptions::path_rewrites`].
+    /// Relative prefixes are supported: `super` works when the generated file is included in a module directly below the crate root,
+    /// e.g. `mod generated { include!(...); }`, and `self` when it is included in the crate root itself.
+    pub root_prefix: String,
+    /// Starts the generated calls with `::` instead of `crate::`, e.g. `::db::init()`, for crates using the 2015 edition.
+    /// This cannot be combined with [`InitOptions::root_prefix`].
+    pub leading_colon: bool,
+    /// Feature gating the generated items. With `Some("init")`, every generated function gets `#[cfg(feature = "init")]`,
+    /// so it only exists when the `init` feature of the crate is enabled.
+    pub feature_gate: Option<String>,
+    /// Additionally generates `generated_init_map() -> BTreeMap<i64, Vec<fn()>>`, mapping every stage to its init functions in call order,
+    /// so the stages can be run on demand. Async init functions cannot be used in the map.
+    pub stage_map: bool,
+    /// Scans only the files reachable through `mod` declarations from `lib.rs` and `main.rs`, instead of every file in the source directory.
+    /// Inline modules and `#[path]` attributes are followed as well, so the generated paths match the actual module tree.
+    /// `RS_INIT_CHANGED` is ignored in this mode.
+    pub follow_module_tree: bool,
+    /// With [`InitOptions::follow_module_tree`], fails if an init function is defined in a file that is not reachable from the module tree,
+    /// instead of silently leaving it out.
+    pub strict_module_tree: bool,
+    /// Name of an additional `#[unsafe(no_mangle)] pub extern "C" fn` calling `generated_init`, so a foreign host can run the init functions.
+    /// In [`InitOptions::fallible`] mode it returns `bool`, which is `false` if an init function failed.
+    /// This cannot be combined with [`InitOptions::asynchronous`] or [`InitOptions::select_stages`].
+    pub ffi_wrapper: Option<String>,
+    /// ABI of [`InitOptions::ffi_wrapper`]. Defaults to `C`.
+    pub ffi_abi: String,
+    /// Strategy of individual stages. Stages that are not listed use [`Strategy::Async`] with [`InitOptions::join_stages`]
+    /// and [`Strategy::Sequential`] otherwise. [`Strategy::Async`] requires [`InitOptions::asynchronous`],
+    /// and is run sequentially by the synchronous `generated_init` of [`InitOptions::sync_and_async`].
+    /// The same applies to [`Strategy::AsyncAggregate`].
+    /// [`Strategy::Threaded`] cannot be used for async functions or in [`InitOptions::fallible`] mode.
+    pub stage_strategy: std::collections::HashMap<i64, Strategy>,
+    /// Init functions the scan cannot find, e.g. in modules generated by macros, as `(path, stage)` pairs like `("crate::generated::init", 2)`.
+    /// They are ordered together with the discovered functions and called as synchronous functions returning `()`.
+    /// A path that does not parse is reported as [`InitError::InvalidOptions`].
+    pub extra_functions: Vec<(String, i64)>,
+    /// In debug builds, prints a warning when the program exits without having called `generated_init`.
+    /// `generated_init` sets a flag that is checked by an `atexit` handler, which is registered through the constructor section
+    /// of the platform (`.init_array` on Linux, Android and FreeBSD, `__mod_init_func` on Apple platforms, `.CRT$XCU` on Windows).
+    pub init_check: bool,
+    /// Writes a human-readable report listing every init function with its stage, module and location in call order.
+    /// Relative paths are resolved from `OUT_DIR`, e.g. `init_report.txt`. The report is only written by [`generate_init_function_with`].
+    pub report_path: Option<PathBuf>,
+    /// Stages of paths used as `stage`, e.g. `("Phase::Db", 1)` for `#[init(stage = Phase::Db)]`.
+    /// The scan cannot evaluate enum discriminants, so every path used as a stage has to be listed exactly as it is written.
+    pub phase_map: std::collections::HashMap<String, i64>,
+    /// Names usable as `#[init(stage = "database")]`, in stage order, e.g. `["logging", "database", "http"]`. The name
+    /// at position N is stage N. If it is not empty, `enum InitStage` is generated with a variant for every name, e.g.
+    /// `InitStage::Database`, and `generated_init_up_to(InitStage)`, which runs the stages up to the given one.
+    pub stage_names: Vec<String>,
+    /// Makes `generated_init` safe to call multiple times from any thread, the init functions run only on the first call.
+    /// Concurrent first callers wait until it has finished. The functions are rendered into `generated_init_uncached`.
+    /// In [`InitOptions::fallible`] mode the outcome is cached in a `OnceLock` and `generated_init` returns `Result<(), &'static E>`,
+    /// so [`InitOptions::error_type`] has to be `Send + Sync`, e.g. `Box<dyn std::error::Error + Send + Sync>`.
+    /// This cannot be combined with [`InitOptions::asynchronous`], [`InitOptions::sync_and_async`] or [`InitOptions::select_stages`].
+    pub run_once: bool,
+    /// Glob patterns matched against the names of the init functions, e.g. `test_*`. Matching functions are left out.
+    pub exclude_fn_patterns: Vec<String>,
+    /// Additionally generates `enum GeneratedStage` with a variant for every discovered stage, e.g. `Stage1` or `StageM1` for stage -1,
+    /// with `GeneratedStage::from_stage(i64)` and `GeneratedStage::stage()` to convert from and to the stage number.
+    pub stage_enum: bool,
+    /// Fails if a scanned module, other than the crate root, contains no init functions.
+    /// Only the modules scanned in this run are checked, modules whose functions are taken from the cache are not.
+    pub require_init_per_module: bool,
+    /// Paths of init functions called before all others, in the listed order, e.g. `crate::logging::init`.
+    /// The functions that are not pinned are ordered by stage between [`InitOptions::pin_first`] and [`InitOptions::pin_last`].
+    /// This cannot be combined with [`InitOptions::split_by_stage`] or [`InitOptions::order_override`].
+    pub pin_first: Vec<String>,
+    /// Paths of init functions called after all others, in the listed order. See [`InitOptions::pin_first`].
+    pub pin_last: Vec<String>,
+    /// Additionally generates a constant with the number of init functions of every stage, e.g. `INIT_STAGE_0_COUNT` or `INIT_STAGE_M1_COUNT` for stage -1.
+    /// Functions behind `#[cfg(...)]` are counted even if they are disabled.
+    pub stage_counts: bool,
+    /// Endings of the file names that are scanned. Defaults to `[".rs"]`, add e.g. `.rs.in` to scan templates as well.
+    /// The module path of `foo.rs.in` is the same as the one of `foo.rs`.
+    pub extensions: Vec<String>,
+    /// Runs concurrent stages with at most this many functions with `tokio::join!` instead of `futures::future::join_all`,
+    /// which avoids allocating. In [`InitOptions::fallible`] mode `tokio::try_join!` is used. Larger stages still use `join_all`,
+    /// as well as stages with functions behind `#[cfg(...)]`. The `tokio` crate with the `macros` feature has to be a dependency.
+    pub join_macro_max: Option<usize>,
+    /// Additionally generates `INIT_IDS: &[(&str, u32)]`, assigning every init function a small id, e.g. for telemetry.
+    /// The ids are assigned by stage and then by path, so they are the same in every build with the same init functions.
+    pub init_ids: bool,
+    /// Name of the attribute marking init functions, `init` by default. It is matched against the last segment of the
+    /// attribute path, so `#[init]` and `#[rs_init_macro::init]` are both found. Teardown functions are marked with the
+    /// name ending in `deinit` instead of `init`, e.g. `#[plugin_deinit]` for `plugin_init`, or with `_deinit` appended.
+    pub attribute_name: String,
+    /// Name of the file written to `OUT_DIR`, `init.rs` by default. Its stem also names the cache, the stage files of
+    /// [`InitOptions::split_by_stage`] and the file of [`InitOptions::explain`], e.g. `plugins_cache.txt`,
+    /// `plugins_stage_1.rs` and `plugins_explain.txt` for `plugins.rs`.
+    pub output_file: String,
+    /// Name of the generated entry point, `generated_init` by default. The variants of the entry point, like
+    /// `generated_init_async` of [`InitOptions::sync_and_async`] and the stage functions, are renamed with it.
+    /// `generated_shutdown` is named after it with `shutdown` in place of the final `init`, e.g. `plugins_shutdown` for
+    /// `plugins_init`, or with `_shutdown` appended. Additional items, like the registry or the metadata, keep their names.
+    pub function_name: String,
+    /// Offsets added to the stage of every function defined under a path prefix, e.g. `("src/plugins", 1000)` to run all
+    /// plugins after the core init without renumbering them. Prefixes are matched by whole path components, and the
+    /// offsets of all matching prefixes are added up.
+    pub stage_offsets: Vec<(String, i64)>,
+    /// Additionally generates `generated_init_steps()`, a stream yielding the path of every init function right before it
+    /// runs, so that the progress can be reported between the steps. Every function runs when the stream is polled after
+    /// yielding its path. The generated code uses the `async-stream` and `futures` crates. In fallible mode the items are
+    /// results and the stream ends after the first error.
+    pub step_stream: bool,
+    /// Prints `cargo:rerun-if-changed` for every scanned file instead of tracking the whole directory.
+    /// [`default_setup_with`] then omits `cargo:rerun-if-changed=src`, so only one of the two is used.
+    /// A new file is noticed once an already tracked file declaring it as a module changes.
+    pub rerun_per_file: bool,
+    /// Additionally generates `generated_init_timed()`, which calls the init functions one after another like a sequential
+    /// `generated_init` and returns the path and the duration of every function, e.g. to fail CI when the init gets too slow.
+    /// In fallible mode it returns a result, in asynchronous mode it is `async`.
+    pub timed: bool,
+    /// Calls every init function through an `#[inline(never)]` trampoline named after its path, e.g.
+    /// `crate__db__connect`, so that it shows up in backtraces even when it is inlined into `generated_init`.
+    /// Asynchronous functions do not need it, their bodies run in futures named after them.
+    pub trampolines: bool,
+    /// Additionally generates `generated_init_resilient()`, which runs every init function in `std::panic::catch_unwind`
+    /// and continues with the next one when it panics, or returns an error in fallible mode. It returns the paths of the
+    /// failed functions, e.g. for plugin loaders where one broken plugin should not stop the others.
+    /// Async functions cannot be used.
+    pub continue_on_panic: bool,
+    /// Function called with the path of every function failing in `generated_init_resilient`, e.g. `crate::report_failure`
+    /// for `fn report_failure(path: &str)`. See [`InitOptions::continue_on_panic`].
+    pub failure_hook: Option<String>,
+    /// Additionally generates `GENERATED_INIT_METADATA: &[(&str, i64, &str)]`, listing the path, the stage and the
+    /// description of every init function in call order. Functions without `#[init(description = "...")]` are described
+    /// by their path.
+    pub metadata: bool,
+    /// Order of the functions within a stage. By default they are sorted by module and name, see [`TieBreak::Module`].
+    pub tie_break: TieBreak,
+    /// Function combining the errors of a [`Strategy::AsyncAggregate`] stage into the error returned by `generated_init`,
+    /// e.g. `crate::InitErrors::new` for `fn new(errors: Vec<E>) -> E`, where `E` is [`InitOptions::error_type`].
+    pub error_aggregator: Option<String>,
+    /// Additionally generates `INIT_TABLE: [fn(); N]`, a fixed-size array of the init functions in call order, for targets
+    /// without heap allocation. Async functions and functions with `#[cfg]` attributes cannot be used, as the length
+    /// is computed during generation.
+    pub init_table: bool,
+    /// Directory the generated files are written to instead of `OUT_DIR`, for build systems other than Cargo.
+    /// The cache and [`InitOptions::report_path`] are written there as well.
+    pub out_dir: Option<PathBuf>,
+    /// Macro invoked with the path and the stage of every init function instead of calling it, e.g. `my_framework::register`
+    /// for `my_framework::register!(crate::db::init, 0);`. The macro decides what to do with the function, so awaiting,
+    /// retries and error propagation are left to it.
+    pub register_macro: Option<String>,
+    /// Warns about functions with a stage above this value, e.g. `stage = 1000000` typed by accident. Disabled by default.
+    pub max_reasonable_stage: Option<i64>,
+    /// Evaluates the `#[cfg]` attributes of the functions and their modules during generation, as if exactly these cfg
+    /// options were enabled, e.g. `["unix", "feature = \"fast\""]`. Disabled functions are left out and the calls of enabled
+    /// ones are not gated. Predicates that cannot be evaluated are kept as attributes. By default all calls are gated.
+    pub active_cfgs: Option<Vec<String>>,
+    /// Function applied to the path of every init function, guard and cleanup function after [`InitOptions::path_rewrites`],
+    /// for rewrites a static map cannot express, e.g. `PathTransform::new(|path| path.replace("::v1::", "::"))`.
+    pub path_transform: Option<PathTransform>,
+    /// Additionally generates `#[test] fn verify_generated_init_order()`, which checks that `GENERATED_INIT_METADATA` still
+    /// lists the functions in the order found during generation and sorted by stage, e.g. to catch edits of the generated code.
+    /// Requires [`InitOptions::metadata`].
+    pub verify_test: bool,
+    /// JSON file listing additional init functions, e.g. generated by a tool written in another language, as
+    /// `[{ "path": "crate::db::init", "stage": 1, "description": "connect to the database" }]`. The description is optional.
+    /// Like [`InitOptions::extra_functions`], they are ordered together with the discovered functions and called as
+    /// synchronous functions returning `()`. The build script is not rerun when the file changes unless it adds
+    /// `cargo:rerun-if-changed` for it.
+    pub sidecar: Option<PathBuf>,
+    /// Marks `generated_init` with `#[cold]` and `#[inline(never)]`, so that the code running once at startup is kept
+    /// out of the way of hot code.
+    pub cold: bool,
+    /// Passes the path of every init function to it, e.g. `crate::db::init("crate::db::init")`, so that it can log its own
+    /// name. The functions must take a single `&'static str` parameter, which is checked during the scan.
+    /// [`InitOptions::extra_functions`] and [`InitOptions::sidecar`] functions are called the same way.
+    pub pass_name: bool,
+    /// Additionally generates `init_plan() -> rs_init::InitPlan`, describing the init functions in call order.
+    /// The generated code refers to `rs_init::InitPlan`, so `rs-init` must also be a regular dependency of the crate including it.
+    pub init_plan: bool,
+    /// Writes errors found in the sources, like a missing or malformed stage, into the generated file as `compile_error!`
+    /// instead of failing the build script, so that they are reported by the build of the crate including it.
+    /// Errors reading or writing files and [`InitError::InvalidOptions`] still fail the build script.
+    pub compile_errors: bool,
+    /// Writes a Graphviz DOT graph of the init functions, labeled with their path and stage, with an edge for every
+    /// dependency declared with `#[init(after = "...")]` or `#[init(before = "...")]`.
+    /// Relative paths are resolved from `OUT_DIR` like [`InitOptions::report_path`], e.g. `init.dot`.
+    pub dot_path: Option<PathBuf>,
+    /// Writes `init_explain.txt` to `OUT_DIR`, named after [`InitOptions::output_file`], listing for every init function its index in the call order and the
+    /// factors deciding it, like its stage, group, pins and the tie break. It is meant for debugging unexpected orders.
+    pub explain: bool,
+}
+
+impl Default for InitOptions {
+    fn default() -> Self {
+        InitOptions {
+            select_stages: false,
+            path_rewrites: Vec::new(),
+            split_by_stage: false,
+            descending: false,
+            asynchronous: false,
+            join_stages: false,
+            timestamp: false,
+            registry: false,
+            indent: "\t".to_string(),
+            group_by_module_first: false,
+            min_stage: None,
+            max_stage: None,
+            sync_and_async: false,
+            split_async: false,
+            order_override: Vec::new(),
+            verbose: false,
+            fallible: false,
+            auto_fallible: false,
+            error_type: "Box<dyn std::error::Error>".to_string(),
+            log_macro: None,
+            log_start_format: "starting {path}".to_string(),
+            log_finish_format: "finished {path}".to_string(),
+            key_aliases: Vec::new(),
+            error_if_empty: false,
+            group_order: Vec::new(),
+            skip_root: false,
+            root_prefix: "crate".to_string(),
+            leading_colon: false,
+            feature_gate: None,
+            stage_map: false,
+            follow_module_tree: false,
+            strict_module_tree: false,
+            ffi_wrapper: None,
+            ffi_abi: "C".to_string(),
+            stage_strategy: std::collections::HashMap::new(),
+            extra_functions: Vec::new(),
+            init_check: false,
+            report_path: None,
+            phase_map: std::collections::HashMap::new(),
+            stage_names: Vec::new(),
+            run_once: false,
+            exclude_fn_patterns: Vec::new(),
+            stage_enum: false,
+            require_init_per_module: false,
+            pin_first: Vec::new(),
+            pin_last: Vec::new(),
+            stage_counts: false,
+            extensions: vec![".rs".to_string()],
+            join_macro_max: None,
+            init_ids: false,
+            attribute_name: "init".to_string(),
+            output_file: "init.rs".to_string(),
+            function_name: "generated_init".to_string(),
+            stage_offsets: Vec::new(),
+            step_stream: false,
+            rerun_per_file: false,
+            timed: false,
+            trampolines: false,
+            continue_on_panic: false,
+            failure_hook: None,
+            metadata: false,
+            tie_break: TieBreak::Module,
+            error_aggregator: None,
+            init_table: false,
+            out_dir: None,
+            register_macro: None,
+            max_reasonable_stage: None,
+            active_cfgs: None,
+            path_transform: None,
+            verify_test: false,
+            sidecar: None,
+            cold: false,
+            pass_name: false,
+            init_plan: false,
+            compile_errors: false,
+            dot_path: None,
+            explain: false,
+        }
+    }
+}
+
+#[derive(Clone)]
 struct InitFunction {
+    /// Path of the called function. It can be overridden with `#[init(call = "...")]`, e.g. to call a wrapper,
+    /// which is then expected to have the same signature as the annotated function.
     call: String,
-    stage: u32,
+    /// Path of the module defining the function.
+    module: String,
+    /// Name of the function, the last segment of its path unless the call is overridden.
+    name: String,
+    stage: i64,
+    returns_value: bool,
+    /// The function returns a `Result`, or a future resolving to one.
+    returns_result: bool,
+    /// How many times the call is repeated after it returned an `Err`, set with `#[init(retries = N)]`.
+    retries: u32,
+    is_async: bool,
+    /// Group set with `#[init(group = "...")]`, see [`InitOptions::group_order`].
+    group: Option<String>,
+    /// Path of a `fn() -> bool` set with `#[init(guard = "...")]`. The function is only called if the guard returns `true`.
+    guard: Option<String>,
+    /// Path of the `fn()` undoing the init function, set with `#[init(cleanup = "...")]`.
+    cleanup: Option<String>,
+    /// Human-readable description set with `#[init(description = "...")]`.
+    description: Option<String>,
+    /// Set with `#[init(first = true)]`, the function runs before all functions without it, regardless of the stage.
+    first: bool,
+    /// Slot filled by the function, set with `#[init(slot = "...")]`. At most one function of a slot can be enabled at once.
+    slot: Option<String>,
+    /// Set for teardown functions marked with `#[deinit(stage = N)]`, which are called by `generated_shutdown` instead.
+    deinit: bool,
+    /// Milliseconds an async function may take, set with `#[init(timeout_ms = N)]`.
+    timeout_ms: Option<u64>,
+    /// Paths of the functions this one depends on, set with `#[init(after = "...")]`.
+    after: Vec<String>,
+    /// Paths of the functions depending on this one, set with `#[init(before = "...")]`.
+    before: Vec<String>,
+    file: String,
+    /// File found in the source directory that defines the function, or includes the file defining it with `include!`.
+    /// In incremental mode the function is dropped and scanned again when this file changes.
+    scanned_from: String,
+    /// Line of the function name in `file`, 0 for [`InitOptions::extra_functions`].
+    line: usize,
+    /// Predicates of the `#[cfg(...)]` attributes of the function, copied to the generated call.
+    cfgs: Vec<String>,
+}
+
+impl InitFunction {
+    /// Creates a function that is not found by the scan, listed in `file` instead, like [`InitOptions::extra_functions`].
+    fn external(call: String, stage: i64, file: &str) -> InitFunction {
+        InitFunction {
+            module: call.rsplit_once("::").map_or("crate", |(module, _)| module).to_string(),
+            name: call.rsplit("::").next().unwrap_or_default().to_string(),
+            call,
+            stage,
+            returns_value: false,
+            returns_result: false,
+            retries: 0,
+            is_async: false,
+            group: None,
+            guard: None,
+            cleanup: None,
+            description: None,
+            first: false,
+            slot: None,
+            deinit: false,
+            timeout_ms: None,
+            after: Vec::new(),
+            before: Vec::new(),
+            file: file.to_string(),
+            scanned_from: file.to_string(),
+            line: 0,
+            cfgs: Vec::new(),
+        }
+    }
+
+    /// Returns the description of the function, or its path if it has none.
+    fn description(&self) -> &str {
+        self.description.as_deref().unwrap_or(&self.call)
+    }
 }
 
+#[derive(Default)]
 struct InitContext {
     functions: Vec<InitFunction>,
+    /// Files parsed during this run. Files taken from the cache are not included.
+    scanned_files: Vec<String>,
+    /// Modules scanned during this run, with the file declaring them.
+    scanned_modules: Vec<(String, String)>,
+    warnings: Vec<String>,
+    /// Files printed as `cargo:rerun-if-changed` with [`InitOptions::rerun_per_file`].
+    tracked_files: Vec<String>,
+    /// Predicates of the `#[cfg(...)]` attributes of `mod name;` declarations found while walking the directory, with the
+    /// declaring file, or the file including it, and the module path. [`prepare`] copies them to the functions of the module and its submodules.
+    module_cfgs: Vec<(String, String, Vec<String>)>,
+    /// Teardown functions marked with `#[deinit(stage = N)]`, moved out of `functions` by [`prepare`] and sorted in the order they are called.
+    shutdown: Vec<InitFunction>,
+    /// Compiled [`InitOptions::exclude_fn_patterns`].
+    exclude_fn_patterns: Vec<glob::Pattern>,
+}
+
+impl InitContext {
+    /// Creates an empty context for a scan with `options`, failing if the options are invalid.
+    fn new(options: &InitOptions) -> Result<InitContext, InitError> {
+        Ok(InitContext { exclude_fn_patterns: check_options(options)?, ..InitContext::default() })
+    }
 }
 
 /// This function is used by the build script to generate the `generated_init` function.
 /// It scans the `src` directory for files with the `#[init]` attribute and generates a function that calls them in the correct order.
 /// The `#[init]` attribute must have a `stage` parameter, which is used to determine the order in which the functions are called.
 /// `cargo:rerun-if-changed=src` is added to the build script output, so that the build script is rerun when any file in the `src` directory changes.
+/// `cargo:rerun-if-env-changed=RS_INIT_CHANGED` is added as well, see [`generate_init_function`] for incremental generation.
 pub fn default_setup() {
-    println!("cargo:rerun-if-changed=src");
-    generate_init_function("src");
+    default_setup_with(&InitOptions::default());
+}
+
+/// Same as [`default_setup`], but the generated code can be customized with [`InitOptions`].
+/// With [`InitOptions::rerun_per_file`], the scanned files are tracked instead of the `src` directory.
+pub fn default_setup_with(options: &InitOptions) {
+    if !options.rerun_per_file {
+        println!("cargo:rerun-if-changed=src");
+    }
+    println!("cargo:rerun-if-env-changed={}", CHANGED_FILES_VAR);
+    generate_init_function_with("src", options);
 }
 
 /// This function is used by the build script to generate the `generated_init` function.
 /// It allows you to specify the directory to scan for files with the `#[init]` attribute.
 /// The `#[init]` attribute must have a `stage` parameter, which is used to determine the order in which the functions are called.
-/// It does not add `cargo:rerun-if-changed=src` to the build script output, so you must add it yourself if you want the build script to be rerun when any file in the `src` directory changes.
+/// It does not add `cargo:rerun-if-changed=src` to the build script output, so you must add it yourself if you want the build script to be rerun when any file in the `src` directory changes,
+/// or enable [`InitOptions::rerun_per_file`].
+///
+/// The results of every scan are cached in `OUT_DIR`. If the `RS_INIT_CHANGED` environment variable holds a list of changed files
+/// (separated like `PATH`), only those files are scanned again and merged with the cached results of the previous run.
+/// Files that no longer exist are removed from the cache. Files included with `include!` are scanned again together with
+/// the file including them. Without the variable, or without a cache for the same source directory, the whole directory is scanned.
+///
+/// Panics if the code cannot be generated, see [`try_generate_init_function`] to handle the error instead.
 pub fn generate_init_function(source_dir: &str) {
-    let out_dir = std::env::var("OUT_DIR").unwrap();
-    let dest_path = std::path::Path::new(&out_dir).join("init.rs");
-    let mut context = InitContext {
-        functions: Vec::new(),
-    };
-    scan_dir(&mut context, source_dir, "crate", 0);
+    generate_init_function_with(source_dir, &InitOptions::default());
+}
+
+/// Same as [`generate_init_function`], but the generated code can be customized with [`InitOptions`].
+pub fn generate_init_function_with(source_dir: &str, options: &InitOptions) {
+    if let Err(error) = try_generate_init_function_with(source_dir, options) {
+        panic!("{}", error);
+    }
+}
+
+/// Same as [`generate_init_function`], but returns an error instead of panicking if the code cannot be generated,
+/// e.g. to print it in a custom format or to fall back to another source directory.
+pub fn try_generate_init_function(source_dir: &str) -> Result<(), InitError> {
+    try_generate_init_function_with(source_dir, &InitOptions::default())
+}
+
+/// Same as [`try_generate_init_function`], but the generated code can be customized with [`InitOptions`].
+pub fn try_generate_init_function_with(source_dir: &str, options: &InitOptions) -> Result<(), InitError> {
+    write_init_function(source_dir, options)
+}
+
+/// Same as [`generate_init_function`], but only the files matching the glob `pattern` are scanned, e.g. `src/**/plugins_*.rs`.
+/// The module paths are computed relative to `src`, files outside of it are skipped with a warning.
+/// The files are always scanned again, `RS_INIT_CHANGED` and the cache are not used.
+///
+/// Panics if the code cannot be generated.
+pub fn generate_init_function_glob(pattern: &str) {
+    generate_init_function_glob_with(pattern, &InitOptions::default());
+}
+
+/// Same as [`generate_init_function_glob`], but the generated code can be customized with [`InitOptions`].
+pub fn generate_init_function_glob_with(pattern: &str, options: &InitOptions) {
+    if let Err(error) = write_init_function_glob(pattern, options) {
+        panic!("{}", error);
+    }
+}
+
+/// Same as [`generate_init_function`], but only the file at `path` is scanned, with `prefix` as its module path, e.g.
+/// `generate_from_file("src/lib.rs", "crate")`. Init functions in inline `mod` blocks are found,
+/// modules in other files are not, so it suits crates bundled into a single file.
+/// The file is always scanned again, `RS_INIT_CHANGED` and the cache are not used.
+///
+/// Panics if the code cannot be generated.
+pub fn generate_from_file(path: &str, prefix: &str) {
+    generate_from_file_with(path, prefix, &InitOptions::default());
+}
+
+/// Same as [`generate_from_file`], but the generated code can be customized with [`InitOptions`].
+pub fn generate_from_file_with(path: &str, prefix: &str, options: &InitOptions) {
+    if let Err(error) = write_init_function_from_file(path, prefix, options) {
+        panic!("{}", error);
+    }
+}
+
+/// Generates a separate pipeline for every binary in `source_dir`, for crates with several binaries in `src/bin`.
+/// Every binary is a separate crate, so its `generated_init` calls only the functions in its own module tree, with paths starting with `crate`.
+/// The code is written to `OUT_DIR/init_<name>.rs`, where the name is `main` for `main.rs`, and for the binaries in `bin` the name
+/// of `bin/<name>.rs` or `bin/<name>/main.rs`. Each binary includes its own file, e.g.
+/// `include!(concat!(env!("OUT_DIR"), "/init_server.rs"));` in `src/bin/server.rs`.
+/// The files are always scanned again, `RS_INIT_CHANGED` and the cache are not used.
+///
+/// Panics if the code cannot be generated.
+pub fn generate_init_function_per_binary(source_dir: &str) {
+    generate_init_function_per_binary_with(source_dir, &InitOptions::default());
+}
+
+/// Same as [`generate_init_function_per_binary`], but the generated code can be customized with [`InitOptions`].
+/// [`InitOptions::split_by_stage`], [`InitOptions::report_path`], [`InitOptions::dot_path`] and [`InitOptions::explain`] are not supported.
+pub fn generate_init_function_per_binary_with(source_dir: &str, options: &InitOptions) {
+    if let Err(error) = write_init_function_per_binary(source_dir, options) {
+        panic!("{}", error);
+    }
+}
 
-    context.functions.sort_by(|a, b| a.stage.cmp(&b.stage));
+/// Returns the code of the `generated_init` function instead of writing it to `OUT_DIR`.
+/// It does not need `OUT_DIR` to be set, so it can also be used outside of build scripts, e.g. to pipe the code into a formatter.
+/// The cache used by [`generate_init_function`] is neither read nor written.
+pub fn render_init(source_dir: &str) -> Result<String, InitError> {
+    render_init_with(source_dir, &InitOptions::default())
+}
+
+/// Same as [`render_init`], but the generated code can be customized with [`InitOptions`].
+pub fn render_init_with(source_dir: &str, options: &InitOptions) -> Result<String, InitError> {
+    let context = collect(source_dir, options, None)?;
+    print_warnings(&context, false);
+    Ok(render(&context, options))
+}
+
+/// Same as [`render_init_with`], but the files are already parsed, e.g. by a linter sharing its parse results.
+/// Every file is given with its module path, like `("crate::db".to_string(), file)`, and its inline modules are scanned as well.
+/// The module path is used in place of the file name in errors and warnings. As the files have no paths, relative paths
+/// in `include!` are resolved from the current directory, and [`InitOptions::stage_offsets`] is not supported.
+pub fn scan_parsed(files: &[(String, syn::File)], options: &InitOptions) -> Result<String, InitError> {
+    if !options.stage_offsets.is_empty() {
+        return Err(InitError::InvalidOptions("stage_offsets cannot be used with scan_parsed, as the files have no paths".to_string()));
+    }
+    let mut context = InitContext::new(options)?;
+    for (prefix, file) in files {
+        if syn::parse_str::<syn::Path>(prefix).is_err() {
+            return Err(InitError::InvalidOptions(format!("{} is not a valid module path", prefix)));
+        }
+        scan_items(&mut context, options, &file.items, prefix, prefix, None)?;
+    }
+    let context = prepare(context, options)?;
+    print_warnings(&context, false);
+    Ok(render(&context, options))
+}
+
+/// Scans `source_dir` and runs all checks done during generation, without generating any code.
+/// Returns the same errors as [`render_init`], so it can be used in CI to detect misconfigured init functions early.
+pub fn validate(source_dir: &str) -> Result<(), InitError> {
+    validate_with(source_dir, &InitOptions::default())
+}
+
+/// Same as [`validate`], but with the given [`InitOptions`] applied.
+pub fn validate_with(source_dir: &str, options: &InitOptions) -> Result<(), InitError> {
+    let context = collect(source_dir, options, None)?;
+    print_warnings(&context, false);
+    Ok(())
+}
+
+/// Returns [`InitOptions::out_dir`], or `OUT_DIR` if it is not set.
+fn out_dir(options: &InitOptions) -> Result<PathBuf, InitError> {
+    match &options.out_dir {
+        Some(out_dir) => Ok(out_dir.clone()),
+        None => std::env::var_os("OUT_DIR").map(PathBuf::from).ok_or(InitError::MissingOutDir),
+    }
+}
+
+fn write_init_function(source_dir: &str, options: &InitOptions) -> Result<(), InitError> {
+    let out_dir = out_dir(options)?;
+    let cache_path = out_dir.join(format!("{}_cache.txt", output_stem(options)));
+    match collect(source_dir, options, Some(&cache_path)) {
+        Ok(context) => write_files(&context, options, &out_dir),
+        Err(error) => write_compile_error(error, options, &out_dir.join(&options.output_file), source_dir),
+    }
+}
+
+fn write_init_function_glob(pattern: &str, options: &InitOptions) -> Result<(), InitError> {
+    let out_dir = out_dir(options)?;
+    match collect_glob(pattern, options) {
+        Ok(context) => write_files(&context, options, &out_dir),
+        Err(error) => write_compile_error(error, options, &out_dir.join(&options.output_file), "src"),
+    }
+}
+
+fn write_init_function_from_file(path: &str, prefix: &str, options: &InitOptions) -> Result<(), InitError> {
+    let out_dir = out_dir(options)?;
+    match collect_file(path, prefix, options) {
+        Ok(context) => write_files(&context, options, &out_dir),
+        Err(error) => write_compile_error(error, options, &out_dir.join(&options.output_file), path),
+    }
+}
+
+/// Writes `error` to `dest_path` as `compile_error!` with [`InitOptions::compile_errors`], otherwise returns it.
+/// Errors in the options and in file access are always returned, as they are mistakes of the build script, not of the sources.
+/// `watched` is printed as `cargo:rerun-if-changed`, as the scanned files are not known to [`InitOptions::rerun_per_file`].
+fn write_compile_error(error: InitError, options: &InitOptions, dest_path: &Path, watched: &str) -> Result<(), InitError> {
+    if !options.compile_errors || matches!(error, InitError::Io { .. } | InitError::MissingOutDir | InitError::InvalidOptions(_)) {
+        return Err(error);
+    }
+    if options.rerun_per_file {
+        println!("cargo:rerun-if-changed={}", watched);
+    }
+    let mut code = header(options);
+    render_feature_gate(&mut code, options);
+    writeln!(code, "compile_error!({:?});", format!("rs-init: {}", error)).unwrap();
+    std::fs::write(dest_path, code).map_err(|error| InitError::Io { path: dest_path.to_path_buf(), error })
+}
+
+fn write_init_function_per_binary(source_dir: &str, options: &InitOptions) -> Result<(), InitError> {
+    let out_dir = out_dir(options)?;
+    if options.split_by_stage || options.report_path.is_some() || options.dot_path.is_some() || options.explain {
+        return Err(InitError::InvalidOptions("split_by_stage, report_path, dot_path and explain cannot be used with one pipeline per binary, as the files of the binaries would overwrite each other".to_string()));
+    }
+    for (name, root) in binary_roots(source_dir)? {
+        let dest_path = out_dir.join(format!("init_{}.rs", name));
+        let context = match collect_binary(&root, options) {
+            Ok(context) => context,
+            Err(error) => {
+                write_compile_error(error, options, &dest_path, source_dir)?;
+                continue;
+            }
+        };
+        print_warnings(&context, true);
+        for file in context.tracked_files.iter() {
+            println!("cargo:rerun-if-changed={}", file);
+        }
+        std::fs::write(&dest_path, render(&context, options)).map_err(|error| InitError::Io { path: dest_path, error })?;
+    }
+    Ok(())
+}
+
+fn write_files(context: &InitContext, options: &InitOptions, out_dir: &Path) -> Result<(), InitError> {
+    print_warnings(context, true);
+    for file in context.tracked_files.iter() {
+        println!("cargo:rerun-if-changed={}", file);
+    }
+    for (file_name, code) in render_files(context, options) {
+        let dest_path = out_dir.join(file_name);
+        std::fs::write(&dest_path, code).map_err(|error| InitError::Io { path: dest_path, error })?;
+    }
+    if let Some(report_path) = &options.report_path {
+        let report_path = out_dir.join(report_path);
+        std::fs::write(&report_path, render_report(context)).map_err(|error| InitError::Io { path: report_path, error })?;
+    }
+    if let Some(dot_path) = &options.dot_path {
+        let dot_path = out_dir.join(dot_path);
+        std::fs::write(&dot_path, render_dot(context)).map_err(|error| InitError::Io { path: dot_path, error })?;
+    }
+    if options.explain {
+        let explain_path = out_dir.join(format!("{}_explain.txt", output_stem(options)));
+        std::fs::write(&explain_path, render_explain(context, options)).map_err(|error| InitError::Io { path: explain_path, error })?;
+    }
+    Ok(())
+}
+
+/// Renders the report written to [`InitOptions::report_path`], listing the init functions in call order.
+fn render_report(context: &InitContext) -> String {
+    let mut report = String::new();
+    writeln!(report, "Init functions in call order, generated by rs-init {}.", env!("CARGO_PKG_VERSION")).unwrap();
+    writeln!(report).unwrap();
+    for (i, function) in context.functions.iter().enumerate() {
+        writeln!(report, "{}. {}", i + 1, function.call).unwrap();
+        writeln!(report, "   stage: {}", function.stage).unwrap();
+        writeln!(report, "   module: {}", function.module).unwrap();
+        writeln!(report, "   location: {}:{}", function.file, function.line).unwrap();
+        if let Some(description) = &function.description {
+            writeln!(report, "   description: {}", description).unwrap();
+        }
+    }
+    report
+}
+
+/// Renders the explanation written with [`InitOptions::explain`].
+/// The factors are listed in the order they are compared by [`sort_functions`], pins last as they are applied last.
+fn render_explain(context: &InitContext, options: &InitOptions) -> String {
+    let mut explain = String::new();
+    writeln!(explain, "Init functions in call order with the factors deciding their position, generated by rs-init {}.", env!("CARGO_PKG_VERSION")).unwrap();
+    for (index, function) in context.functions.iter().enumerate() {
+        writeln!(explain).unwrap();
+        writeln!(explain, "{}", function.call).unwrap();
+        writeln!(explain, "   index: {}", index).unwrap();
+        let mut factors = Vec::new();
+        if let Some(position) = options.order_override.iter().position(|call| *call == function.call) {
+            factors.push(format!("order override, position {}", position));
+        } else {
+            if function.first {
+                factors.push("first = true".to_string());
+            }
+            if !options.group_order.is_empty() {
+                factors.push(match &function.group {
+                    Some(group) => match options.group_order.iter().position(|listed| listed == group) {
+                        Some(position) => format!("group {}, position {} in the group order", group, position),
+                        None => format!("group {}, not in the group order", group),
+                    },
+                    None => "no group, after all groups".to_string(),
+                });
+            }
+            if options.group_by_module_first {
+                factors.push(format!("module {}", function.module));
+            }
+            if options.descending {
+                factors.push(format!("stage {}, descending", function.stage));
+            } else {
+                factors.push(format!("stage {}", function.stage));
+            }
+            factors.push(match options.tie_break {
+                TieBreak::Module => format!("ties broken by module {} and name {}", function.module, function.name),
+                TieBreak::Discovery => format!("ties broken by discovery order, found at {}:{}", function.file, function.line),
+                TieBreak::Path => "ties broken by path".to_string(),
+                TieBreak::Hash => format!("ties broken by path hash {:016x}", path_hash(&function.call)),
+            });
+            if let Some(position) = options.pin_first.iter().position(|call| *call == function.call) {
+                factors.push(format!("pinned first, position {}", position));
+            }
+            if let Some(position) = options.pin_last.iter().position(|call| *call == function.call) {
+                factors.push(format!("pinned last, position {}", position));
+            }
+        }
+        factors.extend(function.after.iter().map(|after| format!("after {}", after)));
+        factors.extend(function.before.iter().map(|before| format!("before {}", before)));
+        for factor in factors {
+            writeln!(explain, "   {}", factor).unwrap();
+        }
+    }
+    explain
+}
 
-    let writer = std::fs::File::create(&dest_path).unwrap();
-    let mut writer = std::io::BufWriter::new(writer);
-    writeln!(writer, "pub fn generated_init() {{").unwrap();
+/// Renders the graph written to [`InitOptions::dot_path`].
+/// Every function is a node, and every dependency an edge from the function running first to the one depending on it.
+fn render_dot(context: &InitContext) -> String {
+    let mut dot = String::new();
+    writeln!(dot, "digraph init {{").unwrap();
+    let mut nodes = Vec::new();
+    for function in context.functions.iter() {
+        // Functions with a stage per cfg are listed once for every stage, they get one node with all of them.
+        if nodes.contains(&function.call.as_str()) {
+            continue;
+        }
+        nodes.push(&function.call);
+        let stages: Vec<String> = context.functions.iter()
+            .filter(|f| f.call == function.call)
+            .map(|f| f.stage.to_string())
+            .collect();
+        writeln!(dot, "\t\"{}\" [label=\"{}\\nstage {}\"];", function.call, function.call, stages.join(", ")).unwrap();
+    }
+    let mut edges = Vec::new();
     for function in context.functions.iter() {
-        writeln!(writer, "\t{};", function.call).unwrap();
+        edges.extend(function.after.iter().map(|after| (after.as_str(), function.call.as_str())));
+        edges.extend(function.before.iter().map(|before| (function.call.as_str(), before.as_str())));
+    }
+    let mut written = Vec::new();
+    for edge in edges {
+        if !written.contains(&edge) {
+            writeln!(dot, "\t\"{}\" -> \"{}\";", edge.0, edge.1).unwrap();
+            written.push(edge);
+        }
+    }
+    writeln!(dot, "}}").unwrap();
+    dot
+}
+
+/// Scans `source_dir` and returns the init functions sorted by stage, with the options applied.
+/// If `cache_path` is given, the cache is used for incremental scanning and updated afterwards.
+fn collect(source_dir: &str, options: &InitOptions, cache_path: Option<&Path>) -> Result<InitContext, InitError> {
+    let mut context = InitContext::new(options)?;
+    // The module paths of changed files cannot be computed without the module tree, so it is always scanned completely.
+    let changed = std::env::var_os(CHANGED_FILES_VAR).filter(|changed| !changed.is_empty() && !options.follow_module_tree);
+    let cached = cache_path.and_then(|cache_path| read_cache(cache_path, &cache_header(source_dir, options)));
+    match (changed, cached) {
+        (Some(changed), Some(cached)) => {
+            let mut changed: Vec<PathBuf> = std::env::split_paths(&changed)
+                .map(|path| normalize_changed_path(&path))
+                .collect();
+            // Included files are scanned again through the files including them.
+            for function in cached.functions.iter() {
+                let is_changed = |file: &str| changed.iter().any(|path| path == Path::new(file));
+                if is_changed(&function.file) && !is_changed(&function.scanned_from) {
+                    changed.push(PathBuf::from(&function.scanned_from));
+                }
+            }
+            context.functions = cached.functions.into_iter()
+                .filter(|f| !changed.iter().any(|path| path == Path::new(&f.scanned_from)))
+                .collect();
+            context.module_cfgs = cached.module_cfgs.into_iter()
+                .filter(|(file, _, _)| !changed.iter().any(|path| path == Path::new(file)))
+                .collect();
+            for path in changed.iter() {
+                if path.is_file() && is_source_file(path, options) && path.starts_with(source_dir) {
+                    scan_source_file(&mut context, options, source_dir, path)?;
+                }
+            }
+        }
+        _ if options.follow_module_tree => {
+            scan_module_tree(&mut context, options, source_dir)?;
+            if options.strict_module_tree {
+                check_reachable(&context, options, source_dir)?;
+            }
+        }
+        _ => scan_dir(&mut context, options, source_dir, source_dir)?,
+    }
+    if !options.follow_module_tree {
+        // `read_dir` order depends on the file system, and the files scanned again in incremental mode come last.
+        // The sort is stable, so the functions of a file and of the files it includes keep their order.
+        context.functions.sort_by(|a, b| Path::new(&a.scanned_from).cmp(Path::new(&b.scanned_from)));
+    }
+    if options.rerun_per_file {
+        // Only the changed files are scanned in incremental mode, so the other files are listed from the directory.
+        if !options.follow_module_tree {
+            list_source_files(&mut context.tracked_files, options, source_dir)?;
+        }
+        track_scanned_files(&mut context, options);
     }
-    writeln!(writer, "}}").unwrap();
+    if let Some(cache_path) = cache_path {
+        write_cache(cache_path, &cache_header(source_dir, options), &context)?;
+    }
+    prepare(context, options)
 }
 
-fn scan_dir(context: &mut InitContext, dir: &str, prefix: &str, level: u32) {
-    let paths = std::fs::read_dir(dir).unwrap();
+/// Scans the files matching the glob `pattern`, with module paths relative to `src`.
+fn collect_glob(pattern: &str, options: &InitOptions) -> Result<InitContext, InitError> {
+    let mut context = InitContext::new(options)?;
+    let paths = glob::glob(pattern).map_err(|error| InitError::InvalidOptions(format!("invalid glob pattern {}: {}", pattern, error)))?;
     for path in paths {
-        let path = path.expect("Failed to read path").path();
-        let path_str = path.to_str().expect("Failed to read path");
-        if path.is_dir() {
-            let dir_name = path.file_name()
-                .expect("Failed to get directory name")
-                .to_str().expect("Failed to get directory name");
-            let prefix = format!("{}::{}", prefix, path.file_name().unwrap().to_str().unwrap());
-            scan_dir(context, path_str, &prefix, level + 1);
+        let path = path.map_err(|error| InitError::Io { path: error.path().to_path_buf(), error: error.into() })?;
+        let path_str = path_to_str(&path)?;
+        if !path.is_file() || !is_source_file(&path, options) {
+            continue;
+        }
+        if !path.starts_with("src") {
+            context.warnings.push(format!("{} is not inside src and is skipped", path_str));
+            continue;
+        }
+        scan_source_file(&mut context, options, "src", &path)?;
+    }
+    if options.rerun_per_file {
+        track_scanned_files(&mut context, options);
+    }
+    prepare(context, options)
+}
+
+/// Scans the single file at `path` with its inline modules, using `prefix` as its module path.
+fn collect_file(path: &str, prefix: &str, options: &InitOptions) -> Result<InitContext, InitError> {
+    if syn::parse_str::<syn::Path>(prefix).is_err() {
+        return Err(InitError::InvalidOptions(format!("{} is not a valid module path", prefix)));
+    }
+    let mut context = InitContext::new(options)?;
+    scan_file(&mut context, options, path, prefix, None)?;
+    if options.rerun_per_file {
+        track_scanned_files(&mut context, options);
+    }
+    prepare(context, options)
+}
+
+/// Follows the module tree of the binary crate rooted at `root`. Modules declared in the root are resolved relative to its directory.
+fn collect_binary(root: &Path, options: &InitOptions) -> Result<InitContext, InitError> {
+    let mut context = InitContext::new(options)?;
+    let module_dir = root.parent().unwrap_or(Path::new(""));
+    scan_file(&mut context, options, path_to_str(root)?, "crate", Some(module_dir))?;
+    if options.rerun_per_file {
+        track_scanned_files(&mut context, options);
+    }
+    prepare(context, options)
+}
+
+/// Returns the names and root files of the binaries in `source_dir`: `main` for `main.rs`, and the binaries in `bin`
+/// named like Cargo does, `bin/name.rs` or `bin/name/main.rs`. The binaries are sorted by name.
+fn binary_roots(source_dir: &str) -> Result<Vec<(String, PathBuf)>, InitError> {
+    let mut roots = Vec::new();
+    let main = Path::new(source_dir).join("main.rs");
+    if main.is_file() {
+        roots.push(("main".to_string(), main));
+    }
+    let bin_dir = Path::new(source_dir).join("bin");
+    if bin_dir.is_dir() {
+        let io_error = |error| InitError::Io { path: bin_dir.clone(), error };
+        let mut bins = Vec::new();
+        for path in std::fs::read_dir(&bin_dir).map_err(io_error)? {
+            let path = path.map_err(io_error)?.path();
+            let root = if path.is_dir() { path.join("main.rs") } else { path.clone() };
+            if !root.is_file() || root.extension() != Some(std::ffi::OsStr::new("rs")) {
+                continue;
+            }
+            let name = path.file_stem().and_then(|name| name.to_str()).ok_or_else(|| InitError::InvalidPath(path.clone()))?;
+            bins.push((name.to_string(), root));
+        }
+        bins.sort();
+        roots.extend(bins);
+    }
+    Ok(roots)
+}
+
+/// Adds the scanned files to the files tracked with [`InitOptions::rerun_per_file`].
+fn track_scanned_files(context: &mut InitContext, options: &InitOptions) {
+    // Included generated code is written on every run, tracking it would rerun the build script every time.
+    let out_dir = out_dir(options).ok();
+    for file in context.scanned_files.iter() {
+        let generated = out_dir.as_ref().is_some_and(|out_dir| Path::new(file).starts_with(out_dir));
+        if !generated && !context.tracked_files.contains(file) {
+            context.tracked_files.push(file.clone());
+        }
+    }
+}
+
+/// Applies the options to the scanned functions, checks them and sorts them in the order they are called.
+fn prepare(mut context: InitContext, options: &InitOptions) -> Result<InitContext, InitError> {
+    if options.require_init_per_module {
+        check_empty_modules(&context)?;
+    }
+    for (call, stage) in options.extra_functions.iter() {
+        context.functions.push(InitFunction::external(call.clone(), *stage, "<extra_functions>"));
+    }
+    for (_, module, cfgs) in context.module_cfgs.iter() {
+        let in_module = |f: &InitFunction| f.module == *module || f.module.starts_with(&format!("{}::", module));
+        for function in context.functions.iter_mut().filter(|f| in_module(f)) {
+            function.cfgs.extend(cfgs.iter().cloned());
+        }
+    }
+    if let Some(max_reasonable_stage) = options.max_reasonable_stage {
+        for function in context.functions.iter().filter(|f| f.stage > max_reasonable_stage) {
+            let warning = format!(
+                "{}:{}: stage {} of {} is above {}, it may be a typo",
+                function.file, function.line, function.stage, function.call, max_reasonable_stage,
+            );
+            context.warnings.push(warning);
+        }
+    }
+    if let Some(active_cfgs) = &options.active_cfgs {
+        let active: Vec<String> = active_cfgs.iter().map(|cfg| normalize_cfg(cfg)).collect();
+        context.functions.retain_mut(|f| {
+            let mut enabled = true;
+            f.cfgs.retain(|cfg| match syn::parse_str::<syn::Meta>(cfg).ok().and_then(|cfg| eval_cfg(&cfg, &active)) {
+                Some(value) => {
+                    enabled &= value;
+                    false
+                }
+                None => true,
+            });
+            enabled
+        });
+    }
+    if let Some(sidecar) = &options.sidecar {
+        load_sidecar(&mut context, sidecar)?;
+    }
+    check_root_prefix(&mut context, options)?;
+    let root = if options.leading_colon { String::new() } else { options.root_prefix.clone() };
+    let root_rewrite = [("crate".to_string(), root)];
+    // Dependencies are rewritten like the calls, so they keep referring to the same functions.
+    let rewrite = |path: &str| {
+        let path = rewrite_path(&rewrite_path(path, &options.path_rewrites), &root_rewrite);
+        match &options.path_transform {
+            Some(PathTransform(transform)) => transform(&path),
+            None => path,
+        }
+    };
+    for function in context.functions.iter_mut() {
+        function.call = rewrite(&function.call);
+        function.guard = function.guard.as_deref().map(rewrite);
+        function.cleanup = function.cleanup.as_deref().map(rewrite);
+        function.after = function.after.iter().map(|path| rewrite(path)).collect();
+        function.before = function.before.iter().map(|path| rewrite(path)).collect();
+    }
+    let (shutdown, functions) = std::mem::take(&mut context.functions).into_iter().partition(|f| f.deinit);
+    context.functions = functions;
+    context.shutdown = shutdown;
+    // Teardown runs in reverse stage order, reversing the stable sort also reverses the discovery order within a stage.
+    context.shutdown.sort_by_key(|f| f.stage);
+    context.shutdown.reverse();
+    context.functions.retain(|f| {
+        options.min_stage.is_none_or(|min_stage| f.stage >= min_stage)
+            && options.max_stage.is_none_or(|max_stage| f.stage <= max_stage)
+    });
+    check_duplicates(&context)?;
+    check_slots(&context)?;
+    let options = &*detect_fallible(&context, options);
+    check_compatibility(&context, options)?;
+    check_options(options)?;
+    sort_functions(&mut context, options)?;
+    if options.verbose {
+        report_discovery(&mut context);
+    }
+    Ok(context)
+}
+
+/// Returns the options with [`InitOptions::fallible`] enabled if [`InitOptions::auto_fallible`] is set and any function returns a `Result`.
+fn detect_fallible<'a>(context: &InitContext, options: &'a InitOptions) -> std::borrow::Cow<'a, InitOptions> {
+    if options.auto_fallible && !options.fallible && context.functions.iter().any(|f| f.returns_result) {
+        std::borrow::Cow::Owned(InitOptions { fallible: true, ..options.clone() })
+    } else {
+        std::borrow::Cow::Borrowed(options)
+    }
+}
+
+/// Entry of the JSON file set with [`InitOptions::sidecar`].
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SidecarEntry {
+    path: String,
+    stage: i64,
+    description: Option<String>,
+}
+
+/// Adds the functions listed in the JSON file at `path`, see [`InitOptions::sidecar`].
+fn load_sidecar(context: &mut InitContext, path: &Path) -> Result<(), InitError> {
+    let content = std::fs::read_to_string(path).map_err(|error| InitError::Io { path: path.to_path_buf(), error })?;
+    let entries: Vec<SidecarEntry> = serde_json::from_str(&content).map_err(|error| InitError::Json { path: path.to_path_buf(), error })?;
+    for entry in entries {
+        if syn::parse_str::<syn::Path>(&entry.path).is_err() {
+            return Err(InitError::InvalidOptions(format!("{}: {} is not a valid path", path.display(), entry.path)));
+        }
+        context.functions.push(InitFunction {
+            description: entry.description,
+            ..InitFunction::external(entry.path, entry.stage, &path.display().to_string())
+        });
+    }
+    Ok(())
+}
+
+/// Normalizes a cfg option like `feature="fast"` to the form used by [`eval_cfg`], `feature = "fast"`.
+fn normalize_cfg(cfg: &str) -> String {
+    match syn::parse_str::<syn::Meta>(cfg) {
+        Ok(syn::Meta::NameValue(syn::MetaNameValue { path, lit: syn::Lit::Str(value), .. })) => {
+            format!("{} = {:?}", path_to_string(&path), value.value())
+        }
+        Ok(syn::Meta::Path(path)) => path_to_string(&path),
+        _ => cfg.to_string(),
+    }
+}
+
+fn path_to_string(path: &syn::Path) -> String {
+    path.segments.iter().map(|segment| segment.ident.to_string()).collect::<Vec<_>>().join("::")
+}
+
+/// Evaluates a cfg predicate with only the `active` options enabled, see [`InitOptions::active_cfgs`].
+/// Returns `None` if the predicate is malformed, so that it is kept as an attribute.
+fn eval_cfg(cfg: &syn::Meta, active: &[String]) -> Option<bool> {
+    match cfg {
+        syn::Meta::Path(path) => Some(active.contains(&path_to_string(path))),
+        syn::Meta::NameValue(syn::MetaNameValue { path, lit: syn::Lit::Str(value), .. }) => {
+            Some(active.contains(&format!("{} = {:?}", path_to_string(path), value.value())))
+        }
+        syn::Meta::List(list) => {
+            let mut values = list.nested.iter().map(|nested| match nested {
+                syn::NestedMeta::Meta(meta) => eval_cfg(meta, active),
+                syn::NestedMeta::Lit(_) => None,
+            });
+            if list.path.is_ident("all") {
+                values.try_fold(true, |all, value| Some(all & value?))
+            } else if list.path.is_ident("any") {
+                values.try_fold(false, |any, value| Some(any | value?))
+            } else if list.path.is_ident("not") && list.nested.len() == 1 {
+                values.next()?.map(|value| !value)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn report_discovery(context: &mut InitContext) {
+    for function in context.functions.iter() {
+        context.warnings.push(format!("found init function {} (stage {}) in {}", function.call, function.stage, function.file));
+    }
+    for file in context.scanned_files.iter() {
+        if !context.functions.iter().any(|f| &f.file == file) {
+            context.warnings.push(format!("{} contributed no init functions", file));
+        }
+    }
+}
+
+/// Prints the warnings collected during the scan, as `cargo:warning` in build scripts and to stderr otherwise.
+fn print_warnings(context: &InitContext, build_script: bool) {
+    for warning in context.warnings.iter() {
+        if build_script {
+            println!("cargo:warning={}", warning);
+        } else {
+            eprintln!("warning: {}", warning);
+        }
+    }
+}
+
+/// Fails if the options contradict each other. Returns the compiled [`InitOptions::exclude_fn_patterns`].
+fn check_options(options: &InitOptions) -> Result<Vec<glob::Pattern>, InitError> {
+    let conflicts = [
+        (options.group_by_module_first, "group_by_module_first"),
+        (options.sync_and_async, "sync_and_async"),
+        (options.split_async, "split_async"),
+        (!options.order_override.is_empty(), "order_override"),
+        (!options.group_order.is_empty(), "group_order"),
+        (!options.pin_first.is_empty() || !options.pin_last.is_empty(), "pin_first and pin_last"),
+    ];
+    if options.split_by_stage {
+        if let Some((_, name)) = conflicts.iter().find(|(enabled, _)| *enabled) {
+            return Err(InitError::InvalidOptions(format!("{} cannot be combined with split_by_stage", name)));
+        }
+    }
+    if options.ffi_wrapper.is_some() && (options.asynchronous || options.select_stages) {
+        return Err(InitError::InvalidOptions("ffi_wrapper cannot be combined with asynchronous or select_stages".to_string()));
+    }
+    let strategies = options.stage_strategy.values();
+    if !options.asynchronous && !options.sync_and_async && !options.split_async && strategies.clone().any(|strategy| *strategy == Strategy::Async) {
+        return Err(InitError::InvalidOptions("Strategy::Async requires asynchronous".to_string()));
+    }
+    if !options.asynchronous && !options.sync_and_async && !options.split_async && strategies.clone().any(|strategy| *strategy == Strategy::AsyncAggregate) {
+        return Err(InitError::InvalidOptions("Strategy::AsyncAggregate requires asynchronous".to_string()));
+    }
+    if options.fallible && options.error_aggregator.is_none() && strategies.clone().any(|strategy| *strategy == Strategy::AsyncAggregate) {
+        return Err(InitError::InvalidOptions("Strategy::AsyncAggregate requires error_aggregator in fallible mode".to_string()));
+    }
+    if options.fallible && strategies.clone().any(|strategy| *strategy == Strategy::Threaded) {
+        return Err(InitError::InvalidOptions("Strategy::Threaded cannot be combined with fallible".to_string()));
+    }
+    if let Some((call, _)) = options.extra_functions.iter().find(|(call, _)| syn::parse_str::<syn::Path>(call).is_err()) {
+        return Err(InitError::InvalidOptions(format!("extra function {} is not a valid path", call)));
+    }
+    if syn::parse_str::<syn::Ident>(&options.function_name).is_err() {
+        return Err(InitError::InvalidOptions(format!("{} is not a valid function name", options.function_name)));
+    }
+    for (i, name) in options.stage_names.iter().enumerate() {
+        if syn::parse_str::<syn::Ident>(&stage_variant(name)).is_err() {
+            return Err(InitError::InvalidOptions(format!("stage name {} cannot be turned into an enum variant", name)));
+        }
+        if options.stage_names[..i].iter().any(|other| stage_variant(other) == stage_variant(name)) {
+            return Err(InitError::InvalidOptions(format!("stage name {} is listed more than once", name)));
+        }
+    }
+    if options.verify_test && !options.metadata {
+        return Err(InitError::InvalidOptions("verify_test requires metadata".to_string()));
+    }
+    if options.run_once && (options.asynchronous || options.sync_and_async || options.split_async || options.select_stages) {
+        return Err(InitError::InvalidOptions("run_once cannot be combined with asynchronous, sync_and_async, split_async or select_stages".to_string()));
+    }
+    if options.split_async && (options.asynchronous || options.sync_and_async) {
+        return Err(InitError::InvalidOptions("split_async cannot be combined with asynchronous or sync_and_async".to_string()));
+    }
+    if options.split_async && (!options.stage_names.is_empty() || options.timed) {
+        return Err(InitError::InvalidOptions("split_async cannot be combined with stage_names or timed".to_string()));
+    }
+    if options.strict_module_tree && !options.follow_module_tree {
+        return Err(InitError::InvalidOptions("strict_module_tree requires follow_module_tree".to_string()));
+    }
+    if !options.order_override.is_empty() && !options.group_order.is_empty() {
+        return Err(InitError::InvalidOptions("group_order cannot be combined with order_override".to_string()));
+    }
+    if !options.order_override.is_empty() && (!options.pin_first.is_empty() || !options.pin_last.is_empty()) {
+        return Err(InitError::InvalidOptions("pin_first and pin_last cannot be combined with order_override".to_string()));
+    }
+    options.exclude_fn_patterns.iter()
+        .map(|pattern| {
+            glob::Pattern::new(pattern)
+                .map_err(|error| InitError::InvalidOptions(format!("invalid function name pattern {}: {}", pattern, error)))
+        })
+        .collect()
+}
+
+/// Fails if a scanned module other than the crate root contains no init functions, see [`InitOptions::require_init_per_module`].
+fn check_empty_modules(context: &InitContext) -> Result<(), InitError> {
+    let empty: Vec<(String, PathBuf)> = context.scanned_modules.iter()
+        .filter(|(module, _)| module != "crate" && !context.functions.iter().any(|f| &f.module == module))
+        .map(|(module, file)| (module.clone(), PathBuf::from(file)))
+        .collect();
+    if empty.is_empty() {
+        Ok(())
+    } else {
+        Err(InitError::EmptyModules(empty))
+    }
+}
+
+/// Fails if [`InitOptions::root_prefix`] is not a path, and warns about relative prefixes, which depend on where the generated file is included.
+fn check_root_prefix(context: &mut InitContext, options: &InitOptions) -> Result<(), InitError> {
+    if options.leading_colon && options.root_prefix != "crate" {
+        return Err(InitError::InvalidOptions("leading_colon cannot be combined with root_prefix".to_string()));
+    }
+    let path = syn::parse_str::<syn::Path>(&options.root_prefix)
+        .map_err(|_| InitError::InvalidOptions(format!("root_prefix {:?} is not a path", options.root_prefix)))?;
+    let location = match path.segments.first() {
+        Some(first) if first.ident == "super" => "in a module directly below the crate root",
+        Some(first) if first.ident == "self" => "in the crate root",
+        _ => return Ok(()),
+    };
+    context.warnings.push(format!(
+        "root_prefix {} is relative, the generated file has to be included {}",
+        options.root_prefix, location,
+    ));
+    Ok(())
+}
+
+/// Sorts the functions in the order they are called.
+fn sort_functions(context: &mut InitContext, options: &InitOptions) -> Result<(), InitError> {
+    if !options.order_override.is_empty() {
+        return apply_order_override(context, &options.order_override);
+    }
+    context.functions.sort_by(|a, b| {
+        let group = if options.group_order.is_empty() {
+            std::cmp::Ordering::Equal
+        } else {
+            group_rank(a, &options.group_order).cmp(&group_rank(b, &options.group_order))
+        };
+        let module = if options.group_by_module_first {
+            a.module.cmp(&b.module)
         } else {
-            if path_str.ends_with(".rs") {
-                if level == 0 {
-                    scan_file(context, path_str, prefix);
+            std::cmp::Ordering::Equal
+        };
+        let stage = if options.descending {
+            b.stage.cmp(&a.stage)
+        } else {
+            a.stage.cmp(&b.stage)
+        };
+        let tie = match options.tie_break {
+            TieBreak::Module => (&a.module, &a.name).cmp(&(&b.module, &b.name)),
+            TieBreak::Discovery => std::cmp::Ordering::Equal,
+            TieBreak::Path => a.call.cmp(&b.call),
+            TieBreak::Hash => (path_hash(&a.call), &a.call).cmp(&(path_hash(&b.call), &b.call)),
+        };
+        // Functions marked with `first` precede everything else and are ordered among themselves as usual.
+        b.first.cmp(&a.first).then(group).then(module).then(stage).then(tie)
+    });
+    apply_pins(context, options)?;
+    sort_dependencies(context, options)
+}
+
+/// Moves functions declared with `#[init(after = "...")]` or `#[init(before = "...")]` behind their dependencies.
+/// The sort is stable: a function is only moved if a dependency requires it, otherwise the order of the other factors is kept.
+fn sort_dependencies(context: &mut InitContext, options: &InitOptions) -> Result<(), InitError> {
+    let functions = &context.functions;
+    // `edges[i]` lists the functions that must run before function `i`. Branches of a conditional stage share their path.
+    let mut edges: Vec<Vec<usize>> = vec![Vec::new(); functions.len()];
+    for (i, function) in functions.iter().enumerate() {
+        for (dependency, is_after) in function.after.iter().map(|path| (path, true)).chain(function.before.iter().map(|path| (path, false))) {
+            let matching: Vec<usize> = (0..functions.len()).filter(|j| functions[*j].call == *dependency).collect();
+            if matching.is_empty() {
+                context.warnings.push(format!("{}:{}: {} depends on {}, which is not an init function", function.file, function.line, function.call, dependency));
+            }
+            for j in matching {
+                if is_after {
+                    edges[i].push(j);
                 } else {
-                    let file_name = path.file_name().expect("Failed to get file name").to_str().expect("Failed to get file name");
-                    let mod_name = &file_name[..file_name.len() - 3];
-                    let prefix = format!("{}::{}", prefix, mod_name);
-                    scan_file(context, path_str, &prefix);
+                    edges[j].push(i);
                 }
             }
         }
     }
+    if edges.iter().all(|edges| edges.is_empty()) || !options.order_override.is_empty() {
+        return Ok(());
+    }
+    let mut placed = vec![false; functions.len()];
+    let mut order = Vec::new();
+    while order.len() < functions.len() {
+        let ready = (0..functions.len()).find(|i| !placed[*i] && edges[*i].iter().all(|j| placed[*j]));
+        let Some(ready) = ready else {
+            return Err(InitError::DependencyCycle(dependency_cycle(functions, &edges, &placed)));
+        };
+        placed[ready] = true;
+        order.push(ready);
+    }
+    if options.split_by_stage && order.iter().enumerate().any(|(position, i)| position != *i) {
+        return Err(InitError::InvalidOptions("dependencies that reorder init functions across their stages cannot be combined with split_by_stage".to_string()));
+    }
+    let mut functions: Vec<Option<InitFunction>> = std::mem::take(&mut context.functions).into_iter().map(Some).collect();
+    context.functions = order.into_iter().filter_map(|i| functions[i].take()).collect();
+    Ok(())
 }
 
-fn attr_to_map(attr: &syn::Attribute) -> std::collections::HashMap<String, String> {
-    let mut map = std::collections::HashMap::new();
-    let tokens = attr.tokens.to_string();
-    let tokens = tokens[1..tokens.len() - 1].trim();
-    let tokens = tokens.split(",");
-    for token in tokens {
-        let token = token.trim();
-        let token = token.split("=");
-        let mut token = token.map(|t| t.trim());
-        let key = token.next().expect("Failed to parse attribute: no key");
-        let value = token.next().expect("Failed to parse attribute: no value");
-        map.insert(key.to_string(), value.to_string());
-    }
-    map
-}
-
-fn scan_file(context: &mut InitContext, path: &str, prefix: &str) {
-    let file_content = std::fs::read_to_string(path).unwrap();
-    let stream = proc_macro2::TokenStream::from_str(&file_content).unwrap();
-    let ast: syn::File = syn::parse2::<syn::File>(stream).unwrap();
-
-    for item in ast.items {
-        if let Item::Fn(f) = item {
-            if let Some(attr) = f.attrs.iter().find(|a| a.path.is_ident("init")) {
-                let name = f.sig.ident.to_string();
-                let call_code = format!("{prefix}::{name}()");
-                let map = attr_to_map(attr);
-                let stage = map.get("stage").expect("No stage parameter defined. It should be a number greater than 0.")
-                    .parse::<u32>().expect("Stage parameter should be a number greater than 0.");
-
-                context.functions.push(InitFunction {
-                    call: call_code,
-                    stage,
-                });
-            }
+/// Returns the paths of a dependency cycle among the functions not `placed` yet in the order they would have to run,
+/// starting and ending with the same path, e.g. `crate::a -> crate::b -> crate::a`.
+fn dependency_cycle(functions: &[InitFunction], edges: &[Vec<usize>], placed: &[bool]) -> Vec<String> {
+    // Every function left has a dependency that is left as well, so following them eventually revisits one.
+    let mut path = vec![(0..functions.len()).find(|i| !placed[*i]).unwrap_or_default()];
+    while let Some(next) = edges[path[path.len() - 1]].iter().copied().find(|j| !placed[*j]) {
+        if let Some(start) = path.iter().position(|i| *i == next) {
+            // Each function in `path` depends on the next one, so the cycle runs backwards through it.
+            let cycle = std::iter::once(next).chain(path[start + 1..].iter().rev().copied()).chain(std::iter::once(next));
+            return cycle.map(|i| functions[i].call.clone()).collect();
         }
+        path.push(next);
     }
+    path.iter().map(|i| functions[*i].call.clone()).collect()
+}
+
+/// FNV-1a hash of `path`. Unlike `DefaultHasher`, it is the same with every Rust version.
+fn path_hash(path: &str) -> u64 {
+    path.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Moves the functions listed in [`InitOptions::pin_first`] and [`InitOptions::pin_last`] to the beginning and the end.
+fn apply_pins(context: &mut InitContext, options: &InitOptions) -> Result<(), InitError> {
+    let unknown: Vec<&str> = options.pin_first.iter().chain(options.pin_last.iter())
+        .filter(|call| !context.functions.iter().any(|f| &f.call == *call))
+        .map(|call| call.as_str())
+        .collect();
+    if !unknown.is_empty() {
+        return Err(InitError::InvalidOptions(format!("pinned functions are not init functions: {}", unknown.join(", "))));
+    }
+    if let Some(call) = options.pin_first.iter().find(|call| options.pin_last.contains(call)) {
+        return Err(InitError::InvalidOptions(format!("{} is pinned both first and last", call)));
+    }
+    context.functions.sort_by_key(|f| {
+        if let Some(position) = options.pin_first.iter().position(|call| *call == f.call) {
+            (0, position)
+        } else if let Some(position) = options.pin_last.iter().position(|call| *call == f.call) {
+            (2, position)
+        } else {
+            (1, 0)
+        }
+    });
+    Ok(())
+}
+
+/// Sort key of the group of `function`: listed groups by position, then the other groups by name, then functions without a group.
+fn group_rank<'a>(function: &'a InitFunction, order: &[String]) -> (usize, bool, Option<&'a str>) {
+    match &function.group {
+        Some(group) => match order.iter().position(|listed| listed == group) {
+            Some(position) => (position, false, None),
+            None => (order.len(), false, Some(group)),
+        },
+        None => (order.len(), true, None),
+    }
+}
+
+fn apply_order_override(context: &mut InitContext, order: &[String]) -> Result<(), InitError> {
+    let unknown: Vec<String> = order.iter()
+        .filter(|call| !context.functions.iter().any(|f| &f.call == *call))
+        .cloned()
+        .collect();
+    let missing: Vec<String> = context.functions.iter()
+        .filter(|f| !order.contains(&f.call))
+        .map(|f| f.call.clone())
+        .collect();
+    if !unknown.is_empty() || !missing.is_empty() {
+        return Err(InitError::OrderOverride { unknown, missing });
+    }
+    context.functions.sort_by_key(|f| order.iter().position(|call| *call == f.call));
+    Ok(())
+}
+
+/// Replaces the `init` at the end of `name` with `suffix`, or appends `_suffix`, e.g. `generated_shutdown` for
+/// `generated_init` and `plugins_shutdown` for `plugins`. Used to name the teardown counterparts of the init items.
+fn with_suffix(name: &str, suffix: &str) -> String {
+    match name.strip_suffix("init") {
+        Some(stem) => format!("{}{}", stem, suffix),
+        None => format!("{}_{}", name, suffix),
+    }
+}
+
+/// Returns the name of [`InitOptions::output_file`] without its extension, e.g. `init` for `init.rs`.
+fn output_stem(options: &InitOptions) -> &str {
+    Path::new(&options.output_file).file_stem().and_then(|stem| stem.to_str()).unwrap_or("init")
+}
+
+/// Renders all files written to `OUT_DIR`. Without [`InitOptions::split_by_stage`] everything is rendered into [`InitOptions::output_file`].
+fn render_files(context: &InitContext, options: &InitOptions) -> Vec<(String, String)> {
+    let options = &*detect_fallible(context, options);
+    if !options.split_by_stage || (options.error_if_empty && context.functions.is_empty()) {
+        return vec![(options.output_file.clone(), render(context, options))];
+    }
+    let mut files = Vec::new();
+    let mut aggregator = header(options);
+    for stage in stages(context) {
+        let file_name = format!("{}_stage_{}.rs", output_stem(options), stage_suffix(stage[0].stage));
+        if options.out_dir.is_some() {
+            // `OUT_DIR` may not be set when the code is compiled, but the stage files are next to this one.
+            writeln!(aggregator, "include!(\"{}\");", file_name).unwrap();
+        } else {
+            writeln!(aggregator, "include!(concat!(env!(\"OUT_DIR\"), \"/{}\"));", file_name).unwrap();
+        }
+        files.push((file_name, header(options) + &render_stage_function(stage, options)));
+    }
+    aggregator.push_str(&render_generated_init(context, options));
+    aggregator.push_str(&render_extras(context, options));
+    files.insert(0, (options.output_file.clone(), aggregator));
+    files.into_iter()
+        .map(|(file_name, code)| (file_name, reindent(&code, &options.indent)))
+        .collect()
+}
+
+/// Renders the generated code as a single string. Stage functions are inlined instead of being included from separate files.
+fn render(context: &InitContext, options: &InitOptions) -> String {
+    let options = &*detect_fallible(context, options);
+    let mut code = header(options);
+    if options.error_if_empty && context.functions.is_empty() {
+        render_feature_gate(&mut code, options);
+        writeln!(code, "compile_error!(\"no #[init] functions found\");").unwrap();
+        return code;
+    }
+    if options.split_by_stage {
+        for stage in stages(context) {
+            code.push_str(&render_stage_function(stage, options));
+        }
+    }
+    code.push_str(&render_generated_init(context, options));
+    code.push_str(&render_extras(context, options));
+    reindent(&code, &options.indent)
+}
+
+/// Replaces the leading tabs of every line with `indent`. The code is rendered with tabs and converted at the end.
+fn reindent(code: &str, indent: &str) -> String {
+    if indent == "\t" {
+        return code.to_string();
+    }
+    let mut result = String::new();
+    for line in code.lines() {
+        let content = line.trim_start_matches('\t');
+        result.push_str(&indent.repeat(line.len() - content.len()));
+        result.push_str(content);
+        result.push('\n');
+    }
+    result
+}
+
+/// Comment at the top of every generated file, stating the version of rs-init that generated it.
+fn header(options: &InitOptions) -> String {
+    let mut code = String::new();
+    writeln!(code, "// Generated by rs-init {}. Do not edit.", env!("CARGO_PKG_VERSION")).unwrap();
+    if options.timestamp {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        writeln!(code, "// Generated at {} (seconds since the Unix epoch).", timestamp).unwrap();
+    }
+    code
+}
+
+fn render_generated_init(context: &InitContext, options: &InitOptions) -> String {
+    if options.sync_and_async {
+        let sync_options = InitOptions { asynchronous: false, ..options.clone() };
+        let async_options = InitOptions { asynchronous: true, ..options.clone() };
+        return render_init_function(context, &sync_options, &options.function_name)
+            + &render_init_function(context, &async_options, &format!("{}_async", options.function_name));
+    }
+    if options.split_async {
+        let split = |is_async: bool| InitContext {
+            functions: context.functions.iter().filter(|f| f.is_async == is_async).cloned().collect(),
+            ..InitContext::default()
+        };
+        let sync_options = InitOptions { asynchronous: false, ..options.clone() };
+        let async_options = InitOptions { asynchronous: true, ..options.clone() };
+        return render_init_function(&split(false), &sync_options, &options.function_name)
+            + &render_init_function(&split(true), &async_options, &format!("{}_async", options.function_name));
+    }
+    if options.run_once {
+        return render_init_function(context, options, &format!("{}_uncached", options.function_name)) + &render_run_once(options);
+    }
+    render_init_function(context, options, &options.function_name)
+}
+
+/// Renders `generated_init` for [`InitOptions::run_once`], which calls `generated_init_uncached` only the first time.
+fn render_run_once(options: &InitOptions) -> String {
+    let mut code = String::new();
+    render_feature_gate(&mut code, options);
+    if options.fallible {
+        writeln!(code, "pub fn {}() -> Result<(), &'static {}> {{", options.function_name, options.error_type).unwrap();
+        writeln!(code, "\tstatic RESULT: std::sync::OnceLock<Result<(), {}>> = std::sync::OnceLock::new();", options.error_type).unwrap();
+        writeln!(code, "\tRESULT.get_or_init({}_uncached).as_ref().map(|_| ())", options.function_name).unwrap();
+    } else {
+        writeln!(code, "pub fn {}() {{", options.function_name).unwrap();
+        writeln!(code, "\tstatic ONCE: std::sync::Once = std::sync::Once::new();").unwrap();
+        writeln!(code, "\tONCE.call_once({}_uncached);", options.function_name).unwrap();
+    }
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+fn render_init_function(context: &InitContext, options: &InitOptions, name: &str) -> String {
+    let mut code = String::new();
+    render_feature_gate(&mut code, options);
+    if options.cold {
+        writeln!(code, "#[cold]").unwrap();
+        writeln!(code, "#[inline(never)]").unwrap();
+    }
+    if options.select_stages {
+        writeln!(code, "{} {}(stages: &[i64]){} {{", fn_prefix(options), name, return_type(options)).unwrap();
+        render_mark_called(&mut code, options);
+        for stage in stages(context) {
+            writeln!(code, "\tif stages.contains(&{}) {{", stage[0].stage).unwrap();
+            render_stage_body(&mut code, stage, options, "\t\t");
+            writeln!(code, "\t}}").unwrap();
+        }
+    } else {
+        writeln!(code, "{} {}(){} {{", fn_prefix(options), name, return_type(options)).unwrap();
+        render_mark_called(&mut code, options);
+        for stage in stages(context) {
+            render_stage_body(&mut code, stage, options, "\t");
+        }
+    }
+    render_fn_end(&mut code, options);
+    code
+}
+
+/// Renders the items generated in addition to `generated_init`.
+fn render_extras(context: &InitContext, options: &InitOptions) -> String {
+    let mut code = String::new();
+    if options.registry {
+        code.push_str(&render_registry(context, options));
+    }
+    if options.stage_map {
+        code.push_str(&render_stage_map(context, options));
+    }
+    if options.init_table {
+        code.push_str(&render_init_table(context, options));
+    }
+    if options.init_check {
+        code.push_str(&render_init_check(options));
+    }
+    if options.stage_enum {
+        code.push_str(&render_stage_enum(context, options));
+    }
+    if !options.stage_names.is_empty() {
+        code.push_str(&render_named_stages(context, options));
+    }
+    if options.step_stream {
+        code.push_str(&render_step_stream(context, options));
+    }
+    if options.trampolines {
+        code.push_str(&render_trampolines(context, options));
+    }
+    if options.continue_on_panic {
+        code.push_str(&render_resilient(context, options));
+    }
+    if options.timed {
+        code.push_str(&render_timed(context, options));
+    }
+    if options.metadata {
+        code.push_str(&render_metadata(context, options));
+    }
+    if options.init_plan {
+        code.push_str(&render_init_plan(context, options));
+    }
+    if options.verify_test {
+        code.push_str(&render_verify_test(context, options));
+    }
+    if options.init_ids {
+        code.push_str(&render_init_ids(context, options));
+    }
+    if options.stage_counts {
+        let mut counts = std::collections::BTreeMap::new();
+        for function in context.functions.iter() {
+            *counts.entry(function.stage).or_insert(0) += 1;
+        }
+        for (stage, count) in counts {
+            render_feature_gate(&mut code, options);
+            writeln!(code, "pub const INIT_STAGE_{}_COUNT: usize = {};", stage_suffix(stage).to_uppercase(), count).unwrap();
+        }
+    }
+    if let Some(name) = &options.ffi_wrapper {
+        code.push_str(&render_ffi_wrapper(options, name));
+    }
+    if context.functions.iter().any(|f| f.cleanup.is_some()) {
+        code.push_str(&render_cleanup(context, options));
+    }
+    if !context.shutdown.is_empty() {
+        code.push_str(&render_shutdown(context, options));
+    }
+    code
+}
+
+/// Renders `generated_shutdown`, calling the functions marked with `#[deinit(stage = N)]` in reverse stage order.
+/// Their results are ignored, so that a failing teardown does not prevent the remaining ones.
+fn render_shutdown(context: &InitContext, options: &InitOptions) -> String {
+    let mut code = String::new();
+    render_feature_gate(&mut code, options);
+    writeln!(code, "pub fn {}() {{", with_suffix(&options.function_name, "shutdown")).unwrap();
+    for function in context.shutdown.iter() {
+        render_cfgs(&mut code, function, "\t");
+        if function.returns_value {
+            writeln!(code, "\tlet _ = {}();", function.call).unwrap();
+        } else {
+            writeln!(code, "\t{}();", function.call).unwrap();
+        }
+    }
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+/// Renders `GENERATED_CLEANUP`, the cleanup functions set with `#[init(cleanup = "...")]` in reverse call order.
+fn render_cleanup(context: &InitContext, options: &InitOptions) -> String {
+    let mut code = String::new();
+    render_feature_gate(&mut code, options);
+    writeln!(code, "pub static GENERATED_CLEANUP: &[fn()] = &[").unwrap();
+    for function in context.functions.iter().rev() {
+        if let Some(cleanup) = &function.cleanup {
+            render_cfgs(&mut code, function, "\t");
+            writeln!(code, "\t{},", cleanup).unwrap();
+        }
+    }
+    writeln!(code, "];").unwrap();
+    code
+}
+
+/// Renders `InitStage` with a variant for every name in [`InitOptions::stage_names`] and `generated_init_up_to`.
+fn render_named_stages(context: &InitContext, options: &InitOptions) -> String {
+    let mut code = String::new();
+    render_feature_gate(&mut code, options);
+    writeln!(code, "#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]").unwrap();
+    writeln!(code, "pub enum InitStage {{").unwrap();
+    for (stage, name) in options.stage_names.iter().enumerate() {
+        writeln!(code, "\t{} = {},", stage_variant(name), stage).unwrap();
+    }
+    writeln!(code, "}}").unwrap();
+    render_feature_gate(&mut code, options);
+    writeln!(code, "{} {}_up_to(stage: InitStage){} {{", fn_prefix(options), options.function_name, return_type(options)).unwrap();
+    for stage in stages(context) {
+        writeln!(code, "\tif {} <= stage as i64 {{", stage[0].stage).unwrap();
+        render_stage_body(&mut code, stage, options, "\t\t");
+        writeln!(code, "\t}}").unwrap();
+    }
+    render_fn_end(&mut code, options);
+    code
+}
+
+/// Converts a stage name to the name of its `InitStage` variant, e.g. `Database` for `database` and `HttpServer` for `http-server`.
+fn stage_variant(name: &str) -> String {
+    name.split(['_', '-', ' '])
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map(|first| first.to_uppercase().chain(chars).collect::<String>()).unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Renders `GeneratedStage` with a variant for every discovered stage, e.g. `Stage1` for stage 1 and `StageM1` for stage -1.
+fn render_stage_enum(context: &InitContext, options: &InitOptions) -> String {
+    let stages: std::collections::BTreeSet<i64> = context.functions.iter().map(|f| f.stage).collect();
+    let variant = |stage: i64| format!("Stage{}", stage_suffix(stage).to_uppercase());
+    let mut code = String::new();
+    render_feature_gate(&mut code, options);
+    writeln!(code, "#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]").unwrap();
+    writeln!(code, "pub enum GeneratedStage {{").unwrap();
+    for stage in stages.iter() {
+        writeln!(code, "\t{},", variant(*stage)).unwrap();
+    }
+    writeln!(code, "}}").unwrap();
+    render_feature_gate(&mut code, options);
+    writeln!(code, "impl GeneratedStage {{").unwrap();
+    writeln!(code, "\tpub fn from_stage(stage: i64) -> Option<Self> {{").unwrap();
+    writeln!(code, "\t\tmatch stage {{").unwrap();
+    for stage in stages.iter() {
+        writeln!(code, "\t\t\t{} => Some(Self::{}),", stage, variant(*stage)).unwrap();
+    }
+    writeln!(code, "\t\t\t_ => None,").unwrap();
+    writeln!(code, "\t\t}}").unwrap();
+    writeln!(code, "\t}}").unwrap();
+    writeln!(code, "\tpub fn stage(self) -> i64 {{").unwrap();
+    writeln!(code, "\t\tmatch self {{").unwrap();
+    for stage in stages.iter() {
+        writeln!(code, "\t\t\tSelf::{} => {},", variant(*stage), stage).unwrap();
+    }
+    writeln!(code, "\t\t}}").unwrap();
+    writeln!(code, "\t}}").unwrap();
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+/// Renders `generated_init_steps`, yielding the path of every function before running it.
+/// Asynchronous functions are always awaited, as the stream body is asynchronous.
+fn render_step_stream(context: &InitContext, options: &InitOptions) -> String {
+    let mut code = String::new();
+    let options = &InitOptions { asynchronous: true, ..options.clone() };
+    render_feature_gate(&mut code, options);
+    if options.fallible {
+        writeln!(code, "pub fn generated_init_steps() -> impl ::futures::Stream<Item = Result<&'static str, {}>> {{", options.error_type).unwrap();
+        writeln!(code, "\t::async_stream::try_stream! {{").unwrap();
+    } else {
+        writeln!(code, "pub fn generated_init_steps() -> impl ::futures::Stream<Item = &'static str> {{").unwrap();
+        writeln!(code, "\t::async_stream::stream! {{").unwrap();
+    }
+    for function in context.functions.iter() {
+        render_cfgs(&mut code, function, "\t\t");
+        writeln!(code, "\t\tyield \"{}\";", function.call).unwrap();
+        render_call(&mut code, function, options, "\t\t");
+    }
+    writeln!(code, "\t}}").unwrap();
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+/// Renders `generated_init_resilient`, running every function in `catch_unwind` and collecting the paths of the failed ones.
+fn render_resilient(context: &InitContext, options: &InitOptions) -> String {
+    let mut code = String::new();
+    render_feature_gate(&mut code, options);
+    writeln!(code, "pub fn generated_init_resilient() -> Vec<String> {{").unwrap();
+    writeln!(code, "\tlet mut failed = Vec::new();").unwrap();
+    for function in context.functions.iter() {
+        render_cfgs(&mut code, function, "\t");
+        writeln!(code, "\t{{").unwrap();
+        writeln!(code, "\t\tlet result = std::panic::catch_unwind(||{} {{", return_type(options)).unwrap();
+        for statement in logged_statements(function, options) {
+            writeln!(code, "\t\t\t{}", statement).unwrap();
+        }
+        if options.fallible {
+            writeln!(code, "\t\t\tOk(())").unwrap();
+        }
+        writeln!(code, "\t\t}});").unwrap();
+        let succeeded = if options.fallible { "Ok(Ok(()))" } else { "Ok(())" };
+        writeln!(code, "\t\tif !matches!(result, {}) {{", succeeded).unwrap();
+        if let Some(hook) = &options.failure_hook {
+            writeln!(code, "\t\t\t{}(\"{}\");", hook, function.call).unwrap();
+        }
+        writeln!(code, "\t\t\tfailed.push(\"{}\".to_string());", function.call).unwrap();
+        writeln!(code, "\t\t}}").unwrap();
+        writeln!(code, "\t}}").unwrap();
+    }
+    writeln!(code, "\tfailed").unwrap();
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+/// Renders `generated_init_timed`, measuring every function including its log statements and guard.
+fn render_timed(context: &InitContext, options: &InitOptions) -> String {
+    let mut code = String::new();
+    let timings = "Vec<(&'static str, std::time::Duration)>";
+    render_feature_gate(&mut code, options);
+    if options.fallible {
+        writeln!(code, "{} generated_init_timed() -> Result<{}, {}> {{", fn_prefix(options), timings, options.error_type).unwrap();
+    } else {
+        writeln!(code, "{} generated_init_timed() -> {} {{", fn_prefix(options), timings).unwrap();
+    }
+    writeln!(code, "\tlet mut timings: {} = Vec::new();", timings).unwrap();
+    for function in context.functions.iter() {
+        render_cfgs(&mut code, function, "\t");
+        writeln!(code, "\t{{").unwrap();
+        writeln!(code, "\t\tlet start = std::time::Instant::now();").unwrap();
+        for statement in logged_statements(function, options) {
+            writeln!(code, "\t\t{}", statement).unwrap();
+        }
+        writeln!(code, "\t\ttimings.push((\"{}\", start.elapsed()));", function.call).unwrap();
+        writeln!(code, "\t}}").unwrap();
+    }
+    if options.fallible {
+        writeln!(code, "\tOk(timings)").unwrap();
+    } else {
+        writeln!(code, "\ttimings").unwrap();
+    }
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+/// Name of the trampoline of `function`, e.g. `crate__db__connect` for `crate::db::connect`.
+fn trampoline_name(function: &InitFunction) -> String {
+    function.call.replace("::", "__")
+}
+
+/// Renders the `#[inline(never)]` trampolines set with [`InitOptions::trampolines`]. The function is passed to its
+/// trampoline at the call site, so the path is resolved there.
+fn render_trampolines(context: &InitContext, options: &InitOptions) -> String {
+    let mut code = String::new();
+    render_feature_gate(&mut code, options);
+    writeln!(code, "#[allow(dead_code, non_snake_case)]").unwrap();
+    writeln!(code, "mod generated_init_trampolines {{").unwrap();
+    let mut names = Vec::new();
+    for function in context.functions.iter() {
+        let name = trampoline_name(function);
+        if names.contains(&name) {
+            continue;
+        }
+        writeln!(code, "\t#[inline(never)]").unwrap();
+        writeln!(code, "\tpub fn {}<T>(f: impl FnOnce() -> T) -> T {{", name).unwrap();
+        writeln!(code, "\t\tf()").unwrap();
+        writeln!(code, "\t}}").unwrap();
+        names.push(name);
+    }
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+/// Renders `GENERATED_INIT_METADATA` in call order, with the `#[cfg]` attributes of every function on its entry.
+fn render_metadata(context: &InitContext, options: &InitOptions) -> String {
+    let mut code = String::new();
+    render_feature_gate(&mut code, options);
+    writeln!(code, "pub const GENERATED_INIT_METADATA: &[(&str, i64, &str)] = &[").unwrap();
+    for function in context.functions.iter() {
+        render_cfgs(&mut code, function, "\t");
+        writeln!(code, "\t(\"{}\", {}, {:?}),", function.call, function.stage, function.description()).unwrap();
+    }
+    writeln!(code, "];").unwrap();
+    code
+}
+
+/// Renders `init_plan`, with the `#[cfg]` attributes of every function on the statement adding its entry.
+fn render_init_plan(context: &InitContext, options: &InitOptions) -> String {
+    let mut code = String::new();
+    let optional = |value: &Option<String>| match value {
+        Some(value) => format!("Some({:?})", value),
+        None => "None".to_string(),
+    };
+    render_feature_gate(&mut code, options);
+    writeln!(code, "pub fn init_plan() -> ::rs_init::InitPlan {{").unwrap();
+    writeln!(code, "\tlet mut entries = Vec::new();").unwrap();
+    for function in context.functions.iter() {
+        render_cfgs(&mut code, function, "\t");
+        writeln!(
+            code, "\tentries.push(::rs_init::InitPlanEntry {{ path: \"{}\", stage: {}, group: {}, description: {} }});",
+            function.call, function.stage, optional(&function.group), optional(&function.description),
+        ).unwrap();
+    }
+    writeln!(code, "\t::rs_init::InitPlan {{ entries }}").unwrap();
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+/// Renders the `#[test]` comparing `GENERATED_INIT_METADATA` with the order found during generation.
+/// The stages are only checked to be sorted if no option moves functions out of the stage order.
+fn render_verify_test(context: &InitContext, options: &InitOptions) -> String {
+    let mut code = String::new();
+    render_feature_gate(&mut code, options);
+    writeln!(code, "#[test]").unwrap();
+    writeln!(code, "fn verify_generated_init_order() {{").unwrap();
+    writeln!(code, "\tlet expected: &[&str] = &[").unwrap();
+    for function in context.functions.iter() {
+        render_cfgs(&mut code, function, "\t\t");
+        writeln!(code, "\t\t\"{}\",", function.call).unwrap();
+    }
+    writeln!(code, "\t];").unwrap();
+    writeln!(code, "\tlet actual: Vec<&str> = GENERATED_INIT_METADATA.iter().map(|(path, _, _)| *path).collect();").unwrap();
+    writeln!(code, "\tassert_eq!(actual, expected);").unwrap();
+    let stage_order = options.order_override.is_empty() && options.group_order.is_empty() && !options.group_by_module_first
+        && options.pin_first.is_empty() && options.pin_last.is_empty()
+        && !context.functions.iter().any(|f| f.first || !f.after.is_empty() || !f.before.is_empty());
+    if stage_order {
+        let comparison = if options.descending { ">=" } else { "<=" };
+        writeln!(code, "\tassert!(GENERATED_INIT_METADATA.windows(2).all(|pair| pair[0].1 {} pair[1].1), \"init functions are not sorted by stage\");", comparison).unwrap();
+    }
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+/// Renders `INIT_IDS`. The ids are assigned in the order of stage and path, so they do not depend on the order in which files are found.
+fn render_init_ids(context: &InitContext, options: &InitOptions) -> String {
+    let mut functions: Vec<(i64, &str)> = context.functions.iter().map(|f| (f.stage, f.call.as_str())).collect();
+    functions.sort();
+    // A function with a conditional stage is listed once, with the id of its earliest stage.
+    let mut seen = std::collections::HashSet::new();
+    functions.retain(|(_, call)| seen.insert(*call));
+    let mut code = String::new();
+    render_feature_gate(&mut code, options);
+    writeln!(code, "pub const INIT_IDS: &[(&str, u32)] = &[").unwrap();
+    for (id, (_, call)) in functions.iter().enumerate() {
+        writeln!(code, "\t(\"{}\", {}),", call, id).unwrap();
+    }
+    writeln!(code, "];").unwrap();
+    code
+}
+
+fn render_mark_called(code: &mut String, options: &InitOptions) {
+    if options.init_check {
+        writeln!(code, "\t#[cfg(debug_assertions)]").unwrap();
+        writeln!(code, "\tgenerated_init_check::CALLED.store(true, std::sync::atomic::Ordering::Relaxed);").unwrap();
+    }
+}
+
+/// Renders the module behind [`InitOptions::init_check`]. A function in the platform's constructor section registers
+/// an `atexit` handler, which prints a warning if the flag set by `generated_init` is still unset.
+fn render_init_check(options: &InitOptions) -> String {
+    let mut code = String::new();
+    render_feature_gate(&mut code, options);
+    writeln!(code, "#[cfg(debug_assertions)]").unwrap();
+    writeln!(code, "mod generated_init_check {{").unwrap();
+    writeln!(code, "\tpub static CALLED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);").unwrap();
+    // `unsafe(...)` is required by the 2024 edition and accepted by the older ones.
+    writeln!(code, "\tunsafe extern \"C\" {{").unwrap();
+    writeln!(code, "\t\tfn atexit(callback: extern \"C\" fn()) -> std::os::raw::c_int;").unwrap();
+    writeln!(code, "\t}}").unwrap();
+    writeln!(code, "\textern \"C\" fn check() {{").unwrap();
+    writeln!(code, "\t\tif !CALLED.load(std::sync::atomic::Ordering::Relaxed) {{").unwrap();
+    writeln!(code, "\t\t\teprintln!(\"warning: the program exited without calling {}\");", options.function_name).unwrap();
+    writeln!(code, "\t\t}}").unwrap();
+    writeln!(code, "\t}}").unwrap();
+    writeln!(code, "\textern \"C\" fn register() {{").unwrap();
+    writeln!(code, "\t\tunsafe {{").unwrap();
+    writeln!(code, "\t\t\tatexit(check);").unwrap();
+    writeln!(code, "\t\t}}").unwrap();
+    writeln!(code, "\t}}").unwrap();
+    writeln!(code, "\t#[used]").unwrap();
+    writeln!(code, "\t#[cfg_attr(any(target_os = \"linux\", target_os = \"android\", target_os = \"freebsd\"), unsafe(link_section = \".init_array\"))]").unwrap();
+    writeln!(code, "\t#[cfg_attr(target_vendor = \"apple\", unsafe(link_section = \"__DATA,__mod_init_func\"))]").unwrap();
+    writeln!(code, "\t#[cfg_attr(windows, unsafe(link_section = \".CRT$XCU\"))]").unwrap();
+    writeln!(code, "\tstatic REGISTER: extern \"C\" fn() = register;").unwrap();
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+fn render_ffi_wrapper(options: &InitOptions, name: &str) -> String {
+    let mut code = String::new();
+    render_feature_gate(&mut code, options);
+    // `unsafe(...)` is required by the 2024 edition and accepted by the older ones.
+    writeln!(code, "#[unsafe(no_mangle)]").unwrap();
+    if options.fallible {
+        writeln!(code, "pub extern {:?} fn {}() -> bool {{", options.ffi_abi, name).unwrap();
+        writeln!(code, "\t{}().is_ok()", options.function_name).unwrap();
+    } else {
+        writeln!(code, "pub extern {:?} fn {}() {{", options.ffi_abi, name).unwrap();
+        writeln!(code, "\t{}();", options.function_name).unwrap();
+    }
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+fn render_stage_map(context: &InitContext, options: &InitOptions) -> String {
+    let mut code = String::new();
+    let options = &InitOptions { fallible: false, ..options.clone() };
+    render_feature_gate(&mut code, options);
+    writeln!(code, "pub fn generated_init_map() -> std::collections::BTreeMap<i64, Vec<fn()>> {{").unwrap();
+    writeln!(code, "\tlet mut map: std::collections::BTreeMap<i64, Vec<fn()>> = std::collections::BTreeMap::new();").unwrap();
+    for function in context.functions.iter() {
+        render_cfgs(&mut code, function, "\t");
+        writeln!(code, "\tmap.entry({}).or_default().push({});", function.stage, fn_pointer(function, options)).unwrap();
+    }
+    writeln!(code, "\tmap").unwrap();
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+/// Renders `INIT_TABLE`, a fixed-size array that needs neither `Vec` nor heap allocation.
+fn render_init_table(context: &InitContext, options: &InitOptions) -> String {
+    let mut code = String::new();
+    let options = &InitOptions { fallible: false, ..options.clone() };
+    render_feature_gate(&mut code, options);
+    writeln!(code, "pub static INIT_TABLE: [fn(); {}] = [", context.functions.len()).unwrap();
+    for function in context.functions.iter() {
+        writeln!(code, "\t{},", fn_pointer(function, options)).unwrap();
+    }
+    writeln!(code, "];").unwrap();
+    code
+}
+
+/// Returns `function` as a `fn()`, either its path or a closure with its statements.
+fn fn_pointer(function: &InitFunction, options: &InitOptions) -> String {
+    let statements = logged_statements(function, options);
+    if statements == [format!("{}();", function.call)] {
+        function.call.clone()
+    } else {
+        format!("|| {{ {} }}", statements.join(" "))
+    }
+}
+
+fn render_registry(context: &InitContext, options: &InitOptions) -> String {
+    let mut code = String::new();
+    let options = &InitOptions { fallible: false, ..options.clone() };
+    render_feature_gate(&mut code, options);
+    writeln!(code, "pub fn generated_init_registry() -> Vec<Box<dyn ::rs_init::InitStep>> {{").unwrap();
+    for (i, function) in context.functions.iter().enumerate() {
+        render_cfgs(&mut code, function, "\t");
+        writeln!(code, "\tstruct Step{};", i).unwrap();
+        render_cfgs(&mut code, function, "\t");
+        writeln!(code, "\timpl ::rs_init::InitStep for Step{} {{", i).unwrap();
+        writeln!(code, "\t\tfn run(&self) {{").unwrap();
+        for statement in logged_statements(function, options) {
+            writeln!(code, "\t\t\t{}", statement).unwrap();
+        }
+        writeln!(code, "\t\t}}").unwrap();
+        writeln!(code, "\t\tfn stage(&self) -> i64 {{").unwrap();
+        writeln!(code, "\t\t\t{}", function.stage).unwrap();
+        writeln!(code, "\t\t}}").unwrap();
+        writeln!(code, "\t\tfn name(&self) -> &'static str {{").unwrap();
+        writeln!(code, "\t\t\t\"{}\"", function.call).unwrap();
+        writeln!(code, "\t\t}}").unwrap();
+        writeln!(code, "\t}}").unwrap();
+    }
+    writeln!(code, "\tlet mut steps: Vec<Box<dyn ::rs_init::InitStep>> = Vec::new();").unwrap();
+    for (i, function) in context.functions.iter().enumerate() {
+        render_cfgs(&mut code, function, "\t");
+        writeln!(code, "\tsteps.push(Box::new(Step{}));", i).unwrap();
+    }
+    writeln!(code, "\tsteps").unwrap();
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+/// Renders the calls of a single stage, or the call of its stage function when the stages are split into separate files.
+fn render_stage_body(code: &mut String, stage: &[InitFunction], options: &InitOptions, indent: &str) {
+    if options.split_by_stage {
+        writeln!(code, "{}{}_stage_{}(){}{};", indent, options.function_name, stage_suffix(stage[0].stage), await_suffix(options.asynchronous), try_suffix(options.fallible)).unwrap();
+    } else {
+        render_stage_calls(code, stage, options, indent);
+    }
+}
+
+fn render_stage_function(stage: &[InitFunction], options: &InitOptions) -> String {
+    let mut code = String::new();
+    render_feature_gate(&mut code, options);
+    writeln!(code, "{} {}_stage_{}(){} {{", fn_prefix(options), options.function_name, stage_suffix(stage[0].stage), return_type(options)).unwrap();
+    render_stage_calls(&mut code, stage, options, "\t");
+    render_fn_end(&mut code, options);
+    code
+}
+
+/// Returns the strategy used to run `stage`, see [`InitOptions::stage_strategy`].
+fn stage_strategy(stage: i64, options: &InitOptions) -> Strategy {
+    let strategy = match options.stage_strategy.get(&stage) {
+        Some(strategy) => *strategy,
+        None if options.join_stages => Strategy::Async,
+        None => Strategy::Sequential,
+    };
+    if matches!(strategy, Strategy::Async | Strategy::AsyncAggregate) && !options.asynchronous {
+        return Strategy::Sequential;
+    }
+    strategy
+}
+
+/// Returns `true` if the concurrent `stage` is run with `tokio::join!`, see [`InitOptions::join_macro_max`].
+/// Macro arguments cannot have `#[cfg]` attributes, so stages with such functions use `join_all`.
+fn uses_join_macro(stage: &[InitFunction], options: &InitOptions) -> bool {
+    options.join_macro_max.is_some_and(|max| stage.len() <= max) && stage.iter().all(|f| f.cfgs.is_empty())
+}
+
+fn render_stage_calls(code: &mut String, stage: &[InitFunction], options: &InitOptions, indent: &str) {
+    let strategy = stage_strategy(stage[0].stage, options);
+    for batch in dependency_batches(stage) {
+        render_batch_calls(code, batch, strategy, options, indent);
+    }
+}
+
+/// Splits a stage into batches run one after another, so that functions depending on each other through
+/// `#[init(after = "...")]` or `#[init(before = "...")]` are not run concurrently. The functions are already sorted
+/// by their dependencies, so a batch ends right before the first function depending on one of its functions.
+fn dependency_batches(stage: &[InitFunction]) -> Vec<&[InitFunction]> {
+    let linked = |a: &InitFunction, b: &InitFunction| a.after.contains(&b.call) || a.before.contains(&b.call)
+        || b.after.contains(&a.call) || b.before.contains(&a.call);
+    let mut batches = Vec::new();
+    let mut start = 0;
+    for (i, function) in stage.iter().enumerate() {
+        if stage[start..i].iter().any(|other| linked(function, other)) {
+            batches.push(&stage[start..i]);
+            start = i;
+        }
+    }
+    batches.push(&stage[start..]);
+    batches
+}
+
+/// Renders the calls of a batch of functions of the same stage, see [`dependency_batches`].
+fn render_batch_calls(code: &mut String, stage: &[InitFunction], strategy: Strategy, options: &InitOptions, indent: &str) {
+    if strategy == Strategy::Threaded && stage.len() > 1 {
+        writeln!(code, "{}std::thread::scope(|scope| {{", indent).unwrap();
+        for function in stage {
+            render_cfgs(code, function, &format!("{}\t", indent));
+            writeln!(code, "{}\tscope.spawn(|| {{ {} }});", indent, logged_statements(function, options).join(" ")).unwrap();
+        }
+        writeln!(code, "{}}});", indent).unwrap();
+    } else if strategy == Strategy::Async && stage.len() > 1 && uses_join_macro(stage, options) {
+        let futures: Vec<String> = stage.iter()
+            .map(|function| {
+                let statements = logged_statements(function, options);
+                if options.fallible {
+                    format!("async {{ {} Ok::<(), {}>(()) }}", statements.join(" "), options.error_type)
+                } else if statements == [format!("{}().await;", function.call)] {
+                    format!("{}()", function.call)
+                } else {
+                    format!("async {{ {} }}", statements.join(" "))
+                }
+            })
+            .collect();
+        if options.fallible {
+            writeln!(code, "{}::tokio::try_join!({})?;", indent, futures.join(", ")).unwrap();
+        } else {
+            writeln!(code, "{}::tokio::join!({});", indent, futures.join(", ")).unwrap();
+        }
+    } else if matches!(strategy, Strategy::Async | Strategy::AsyncAggregate) && stage.len() > 1 {
+        let output = if options.fallible {
+            format!("Result<(), {}>", options.error_type)
+        } else {
+            "()".to_string()
+        };
+        writeln!(code, "{}{{", indent).unwrap();
+        writeln!(code, "{}\tlet mut stage: Vec<std::pin::Pin<Box<dyn std::future::Future<Output = {}>>>> = Vec::new();", indent, output).unwrap();
+        for function in stage {
+            render_cfgs(code, function, &format!("{}\t", indent));
+            if options.fallible {
+                writeln!(code, "{}\tstage.push(Box::pin(async {{ {} Ok::<(), {}>(()) }}));", indent, logged_statements(function, options).join(" "), options.error_type).unwrap();
+            } else {
+                writeln!(code, "{}\tstage.push(Box::pin(async {{ {} }}));", indent, logged_statements(function, options).join(" ")).unwrap();
+            }
+        }
+        if let (true, Strategy::AsyncAggregate, Some(aggregator)) = (options.fallible, strategy, &options.error_aggregator) {
+            writeln!(code, "{}\tlet errors: Vec<{}> = ::futures::future::join_all(stage).await.into_iter().filter_map(Result::err).collect();", indent, options.error_type).unwrap();
+            writeln!(code, "{}\tif !errors.is_empty() {{", indent).unwrap();
+            writeln!(code, "{}\t\treturn Err({}(errors));", indent, aggregator).unwrap();
+            writeln!(code, "{}\t}}", indent).unwrap();
+        } else if options.fallible {
+            writeln!(code, "{}\tfor result in ::futures::future::join_all(stage).await {{", indent).unwrap();
+            writeln!(code, "{}\t\tresult?;", indent).unwrap();
+            writeln!(code, "{}\t}}", indent).unwrap();
+        } else {
+            writeln!(code, "{}\t::futures::future::join_all(stage).await;", indent).unwrap();
+        }
+        writeln!(code, "{}}}", indent).unwrap();
+    } else {
+        for function in stage {
+            render_call(code, function, options, indent);
+        }
+    }
+}
+
+/// Returns the beginning of the signature of generated functions.
+fn fn_prefix(options: &InitOptions) -> &'static str {
+    if options.asynchronous {
+        "pub async fn"
+    } else {
+        "pub fn"
+    }
+}
+
+/// Returns the return type of generated functions, including the arrow.
+fn return_type(options: &InitOptions) -> String {
+    if options.fallible {
+        format!(" -> Result<(), {}>", options.error_type)
+    } else {
+        String::new()
+    }
+}
+
+/// Closes a generated function, returning `Ok(())` in fallible mode.
+fn render_fn_end(code: &mut String, options: &InitOptions) {
+    if options.fallible {
+        writeln!(code, "\tOk(())").unwrap();
+    }
+    writeln!(code, "}}").unwrap();
+}
+
+fn try_suffix(fallible: bool) -> &'static str {
+    if fallible {
+        "?"
+    } else {
+        ""
+    }
+}
+
+fn await_suffix(is_async: bool) -> &'static str {
+    if is_async {
+        ".await"
+    } else {
+        ""
+    }
+}
+
+/// Groups the sorted functions by stage.
+fn stages(context: &InitContext) -> std::slice::ChunkBy<'_, InitFunction, impl FnMut(&InitFunction, &InitFunction) -> bool> {
+    context.functions.chunk_by(|a, b| a.stage == b.stage)
+}
+
+/// Formats a stage for use in file and function names, e.g. `2` or `m1` for `-1`.
+fn stage_suffix(stage: i64) -> String {
+    if stage < 0 {
+        format!("m{}", stage.unsigned_abs())
+    } else {
+        stage.to_string()
+    }
+}
+
+fn render_call(code: &mut String, function: &InitFunction, options: &InitOptions, indent: &str) {
+    for statement in logged_statements(function, options) {
+        render_cfgs(code, function, indent);
+        writeln!(code, "{}{}", indent, statement).unwrap();
+    }
+}
+
+/// Returns the call statement of `function`, surrounded by log statements if [`InitOptions::log_macro`] is set.
+/// If the function has a guard, everything is wrapped in a single `if` statement.
+fn logged_statements(function: &InitFunction, options: &InitOptions) -> Vec<String> {
+    let call = call_statement(function, options);
+    let statements = match &options.log_macro {
+        Some(log_macro) => {
+            // The message is used as a format string, so braces in the description are escaped.
+            let description = function.description().replace('{', "{{").replace('}', "}}");
+            let log = |format: &str| format!("{}!({:?});", log_macro, format.replace("{path}", &function.call).replace("{description}", &description));
+            vec![log(&options.log_start_format), call, log(&options.log_finish_format)]
+        }
+        None => vec![call],
+    };
+    match &function.guard {
+        Some(guard) => vec![format!("if {}() {{ {} }}", guard, statements.join(" "))],
+        None => statements,
+    }
+}
+
+fn render_feature_gate(code: &mut String, options: &InitOptions) {
+    if let Some(feature) = &options.feature_gate {
+        writeln!(code, "#[cfg(feature = {:?})]", feature).unwrap();
+    }
+}
+
+/// Renders the `#[cfg(...)]` attribute of `function`, combining the predicates of the function and its modules with `all`.
+fn render_cfgs(code: &mut String, function: &InitFunction, indent: &str) {
+    match function.cfgs.as_slice() {
+        [] => {}
+        [cfg] => writeln!(code, "{}#[cfg({})]", indent, cfg).unwrap(),
+        cfgs => writeln!(code, "{}#[cfg(all({}))]", indent, cfgs.join(", ")).unwrap(),
+    }
+}
+
+/// Returns the statement calling `function`, awaiting it in asynchronous mode and propagating its error in fallible mode.
+fn call_statement(function: &InitFunction, options: &InitOptions) -> String {
+    if let Some(register_macro) = &options.register_macro {
+        return format!("{}!({}, {});", register_macro, function.call, function.stage);
+    }
+    let timeout = function.timeout_ms.filter(|_| options.asynchronous && function.is_async);
+    let await_suffix = match timeout {
+        // The future is awaited by the timeout, which fails with its own error when it elapses.
+        Some(_) => "",
+        None => await_suffix(options.asynchronous && function.is_async),
+    };
+    let call = match (options.trampolines, options.pass_name) {
+        (true, true) => format!("generated_init_trampolines::{}(|| {}(\"{}\")){}", trampoline_name(function), function.call, function.call, await_suffix),
+        (true, false) => format!("generated_init_trampolines::{}({}){}", trampoline_name(function), function.call, await_suffix),
+        (false, true) => format!("{}(\"{}\"){}", function.call, function.call, await_suffix),
+        (false, false) => format!("{}(){}", function.call, await_suffix),
+    };
+    let call = match timeout {
+        Some(timeout_ms) => format!("::tokio::time::timeout(::std::time::Duration::from_millis({}), {}).await?", timeout_ms, call),
+        None => call,
+    };
+    if options.fallible && function.retries > 0 {
+        format!(
+            "{{ let mut attempt = 0; loop {{ match {} {{ Ok(_) => break, Err(_) if attempt < {} => attempt += 1, Err(error) => Err(error)? }} }} }}",
+            call, function.retries,
+        )
+    } else if options.fallible && function.returns_result {
+        format!("{}?;", call)
+    } else if function.returns_value {
+        format!("let _ = {};", call)
+    } else {
+        format!("{};", call)
+    }
+}
+
+/// Fails if two functions would be called through the same path.
+fn check_duplicates(context: &InitContext) -> Result<(), InitError> {
+    let mut seen = std::collections::HashMap::new();
+    for function in context.functions.iter() {
+        // The branches of a conditional stage are the same function with different cfgs.
+        let location = (function.file.as_str(), function.line);
+        if let Some((file, _)) = seen.insert(function.call.as_str(), location).filter(|seen| *seen != location) {
+            return Err(InitError::DuplicateFunction {
+                call: function.call.clone(),
+                files: vec![PathBuf::from(file), PathBuf::from(&function.file)],
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Fails if two functions of the same slot can be enabled at once, see [`InitFunction::slot`].
+/// Functions are exclusive if one of them has a `cfg` predicate that the other one negates with `not(...)`.
+/// Predicates evaluated with [`InitOptions::active_cfgs`] are already removed, along with the disabled functions.
+fn check_slots(context: &InitContext) -> Result<(), InitError> {
+    for (i, a) in context.functions.iter().enumerate() {
+        for b in context.functions[i + 1..].iter() {
+            let same_function = (a.file.as_str(), a.line) == (b.file.as_str(), b.line);
+            if a.slot.is_some() && a.slot == b.slot && !same_function && !cfgs_exclusive(&a.cfgs, &b.cfgs) {
+                return Err(InitError::SlotConflict {
+                    slot: a.slot.clone().unwrap_or_default(),
+                    calls: vec![a.call.clone(), b.call.clone()],
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns `true` if a predicate in `a` is negated in `b` or the other way around, so both cannot be enabled at once.
+fn cfgs_exclusive(a: &[String], b: &[String]) -> bool {
+    // Predicates are compared as parsed tokens, so differences in whitespace do not matter.
+    let parse = |cfg: &String| syn::parse_str::<syn::Meta>(cfg).ok();
+    let negates = |a: &[String], b: &[String]| a.iter().filter_map(parse).any(|a| match a {
+        syn::Meta::List(list) if list.path.is_ident("not") && list.nested.len() == 1 => {
+            let negated = quote::ToTokens::to_token_stream(&list.nested[0]).to_string();
+            b.iter().filter_map(parse).any(|b| quote::ToTokens::to_token_stream(&b).to_string() == negated)
+        }
+        _ => false,
+    });
+    negates(a, b) || negates(b, a)
+}
+
+/// Fails if a function cannot be called in the code generated with the given options.
+fn check_compatibility(context: &InitContext, options: &InitOptions) -> Result<(), InitError> {
+    for function in context.functions.iter() {
+        if options.registry && function.is_async {
+            return Err(InitError::Incompatible {
+                call: function.call.clone(),
+                message: "async functions cannot be used in the registry".to_string(),
+            });
+        }
+        if options.stage_strategy.get(&function.stage) == Some(&Strategy::Threaded) && function.is_async {
+            return Err(InitError::Incompatible {
+                call: function.call.clone(),
+                message: format!("async functions cannot be run by the threaded stage {}", function.stage),
+            });
+        }
+        if options.stage_map && function.is_async {
+            return Err(InitError::Incompatible {
+                call: function.call.clone(),
+                message: "async functions cannot be used in the stage map".to_string(),
+            });
+        }
+        if options.init_table && function.is_async {
+            return Err(InitError::Incompatible {
+                call: function.call.clone(),
+                message: "async functions cannot be used in the init table".to_string(),
+            });
+        }
+        if options.init_table && !function.cfgs.is_empty() {
+            return Err(InitError::Incompatible {
+                call: function.call.clone(),
+                message: "functions with #[cfg] attributes cannot be used in the init table, as its length is fixed".to_string(),
+            });
+        }
+        if function.timeout_ms.is_some() && !((options.asynchronous || options.split_async) && options.fallible) {
+            return Err(InitError::Incompatible {
+                call: function.call.clone(),
+                message: "timeouts can only be used in asynchronous fallible mode, which returns the error of an elapsed timeout".to_string(),
+            });
+        }
+        if options.continue_on_panic && function.is_async {
+            return Err(InitError::Incompatible {
+                call: function.call.clone(),
+                message: "async functions cannot be run with continue_on_panic".to_string(),
+            });
+        }
+        if !options.fallible && function.retries > 0 {
+            return Err(InitError::Incompatible {
+                call: function.call.clone(),
+                message: "retries can only be used with the fallible option".to_string(),
+            });
+        }
+        if options.sync_and_async && function.is_async {
+            return Err(InitError::Incompatible {
+                call: function.call.clone(),
+                message: "async functions cannot be called from the synchronous generated_init".to_string(),
+            });
+        }
+        if function.is_async && !options.asynchronous && !options.split_async && options.register_macro.is_none() {
+            return Err(InitError::Incompatible {
+                call: function.call.clone(),
+                message: "async functions can only be called with asynchronous or split_async, the synchronous generated_init would drop their futures".to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Applies the longest matching rewrite from [`InitOptions::path_rewrites`] to `path`.
+fn rewrite_path(path: &str, rewrites: &[(String, String)]) -> String {
+    rewrites.iter()
+        .filter(|(source, _)| path == source || path.starts_with(&format!("{}::", source)))
+        .max_by_key(|(source, _)| source.len())
+        .map(|(source, target)| format!("{}{}", target, &path[source.len()..]))
+        .unwrap_or_else(|| path.to_string())
+}
+
+fn scan_dir(context: &mut InitContext, options: &InitOptions, source_dir: &str, dir: &str) -> Result<(), InitError> {
+    for path in sorted_dir(dir)? {
+        if path.is_dir() {
+            scan_dir(context, options, source_dir, path_to_str(&path)?)?;
+        } else if is_source_file(&path, options) {
+            scan_source_file(context, options, source_dir, &path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the paths of the entries of `dir` sorted by name, as `read_dir` returns them in an order depending on the file system.
+fn sorted_dir(dir: &str) -> Result<Vec<PathBuf>, InitError> {
+    let io_error = |error| InitError::Io { path: PathBuf::from(dir), error };
+    let mut paths = std::fs::read_dir(dir).map_err(io_error)?
+        .map(|entry| entry.map(|entry| entry.path()).map_err(io_error))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+/// Lists the source files in `dir` and its subdirectories without scanning them.
+fn list_source_files(files: &mut Vec<String>, options: &InitOptions, dir: &str) -> Result<(), InitError> {
+    for path in sorted_dir(dir)? {
+        if path.is_dir() {
+            list_source_files(files, options, path_to_str(&path)?)?;
+        } else if is_source_file(&path, options) {
+            files.push(path_to_str(&path)?.to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Scans a file found in `source_dir`. The crate root is skipped with [`InitOptions::skip_root`].
+fn scan_source_file(context: &mut InitContext, options: &InitOptions, source_dir: &str, path: &Path) -> Result<(), InitError> {
+    let prefix = module_prefix(source_dir, path)?;
+    if options.skip_root && prefix == "crate" {
+        return Ok(());
+    }
+    let path = path_to_str(path)?;
+    let start = (context.functions.len(), context.module_cfgs.len());
+    scan_file(context, options, path, &prefix, None)?;
+    // Everything found in included files is dropped with the file including them in incremental mode.
+    for function in context.functions[start.0..].iter_mut() {
+        function.scanned_from = path.to_string();
+    }
+    for (file, _, _) in context.module_cfgs[start.1..].iter_mut() {
+        *file = path.to_string();
+    }
+    Ok(())
+}
+
+/// Checks the file name against [`InitOptions::extensions`]. Only the file name is used, so it does not depend on the path separator of the platform.
+fn is_source_file(path: &Path, options: &InitOptions) -> bool {
+    path.file_name()
+        .and_then(|file_name| file_name.to_str())
+        .is_some_and(|file_name| options.extensions.iter().any(|extension| file_name.ends_with(extension.as_str())))
+}
+
+fn path_to_str(path: &Path) -> Result<&str, InitError> {
+    path.to_str().ok_or_else(|| InitError::InvalidPath(path.to_path_buf()))
+}
+
+/// Computes the module path of a file inside `source_dir`.
+/// `lib.rs` and `main.rs` in `source_dir` are the crate root. Other files get a module path built from the directories and the file name,
+/// except `mod.rs`, which belongs to the module of its directory. This way both `foo.rs` and `foo/mod.rs` map to `crate::foo`.
+/// The path is split into [`std::path::Component`]s, so both separators work on Windows and `.` components are ignored.
+fn module_prefix(source_dir: &str, path: &Path) -> Result<String, InitError> {
+    let relative = path.strip_prefix(source_dir)
+        .map_err(|_| InitError::InvalidOptions(format!("{} is not inside the source directory {}", path.display(), source_dir)))?;
+    let components = relative.components()
+        .filter_map(|c| match c {
+            std::path::Component::Normal(c) => Some(c.to_str().ok_or_else(|| InitError::InvalidPath(path.to_path_buf()))),
+            _ => None,
+        })
+        .collect::<Result<Vec<&str>, InitError>>()?;
+    let mut prefix = String::from("crate");
+    if let Some((file_name, dirs)) = components.split_last() {
+        for dir in dirs {
+            prefix = format!("{}::{}", prefix, dir);
+        }
+        // Module names cannot contain dots, so everything after the first one is the extension, e.g. `.rs.in`.
+        let mod_name = file_name.split('.').next().unwrap_or(file_name);
+        let is_root = dirs.is_empty() && (mod_name == "lib" || mod_name == "main");
+        if mod_name != "mod" && !is_root {
+            prefix = format!("{}::{}", prefix, mod_name);
+        }
+    }
+    Ok(prefix)
+}
+
+/// Makes an absolute path from `RS_INIT_CHANGED` relative to the current directory, so it can be compared with the scanned paths.
+fn normalize_changed_path(path: &Path) -> PathBuf {
+    if let Ok(current_dir) = std::env::current_dir() {
+        if let Ok(relative) = path.strip_prefix(&current_dir) {
+            return relative.to_path_buf();
+        }
+    }
+    path.to_path_buf()
+}
+
+/// Reads the functions cached by the previous run. Returns `None` if there is no cache or if it was created for a different source directory.
+fn read_cache(cache_path: &Path, header: &str) -> Option<InitContext> {
+    let content = std::fs::read_to_string(cache_path).ok()?;
+    let mut lines = content.lines();
+    if lines.next()? != header {
+        return None;
+    }
+    let mut cached = InitContext::default();
+    for line in lines {
+        if let Some(module_cfgs) = line.strip_prefix("mod\t") {
+            let mut fields = module_cfgs.split('\t');
+            let file = fields.next()?.to_string();
+            let module = fields.next()?.to_string();
+            cached.module_cfgs.push((file, module, fields.map(|cfg| cfg.to_string()).collect()));
+            continue;
+        }
+        let mut fields = line.split('\t');
+        let file = fields.next()?.to_string();
+        let scanned_from = fields.next()?.to_string();
+        let line = fields.next()?.parse().ok()?;
+        let stage = fields.next()?.parse().ok()?;
+        let returns_value = fields.next()?.parse().ok()?;
+        let returns_result = fields.next()?.parse().ok()?;
+        let retries = fields.next()?.parse().ok()?;
+        let is_async = fields.next()?.parse().ok()?;
+        let group = Some(fields.next()?.to_string()).filter(|group| !group.is_empty());
+        let guard = Some(fields.next()?.to_string()).filter(|guard| !guard.is_empty());
+        let cleanup = Some(fields.next()?.to_string()).filter(|cleanup| !cleanup.is_empty());
+        let description = match fields.next()? {
+            "" => None,
+            description => Some(string_value(description)?),
+        };
+        let first = fields.next()?.parse().ok()?;
+        let slot = Some(fields.next()?.to_string()).filter(|slot| !slot.is_empty());
+        let deinit = fields.next()?.parse().ok()?;
+        let timeout_ms = match fields.next()? {
+            "" => None,
+            timeout_ms => Some(timeout_ms.parse().ok()?),
+        };
+        let after = fields.next()?.split(',').filter(|path| !path.is_empty()).map(|path| path.to_string()).collect();
+        let before = fields.next()?.split(',').filter(|path| !path.is_empty()).map(|path| path.to_string()).collect();
+        let module = fields.next()?.to_string();
+        let name = fields.next()?.to_string();
+        let call = fields.next()?.to_string();
+        let cfgs = fields.map(|cfg| cfg.to_string()).collect();
+        cached.functions.push(InitFunction {
+            call,
+            module,
+            name,
+            stage,
+            returns_value,
+            returns_result,
+            retries,
+            is_async,
+            group,
+            guard,
+            cleanup,
+            description,
+            first,
+            slot,
+            deinit,
+            timeout_ms,
+            after,
+            before,
+            file,
+            scanned_from,
+            line,
+            cfgs,
+        });
+    }
+    Some(cached)
+}
+
+/// First line of the cache. The cache is discarded when the version, the source directory or an option affecting the scan changes.
+fn cache_header(source_dir: &str, options: &InitOptions) -> String {
+    let phase_map: std::collections::BTreeMap<_, _> = options.phase_map.iter().collect();
+    format!(
+        "{}\t{}\t{}\t{}\t{:?}\t{}\t{}\t{:?}\t{:?}\t{:?}\t{:?}\t{:?}",
+        CACHE_VERSION, source_dir, options.pass_name, options.attribute_name, options.key_aliases, options.skip_root, options.follow_module_tree, phase_map, options.exclude_fn_patterns, options.extensions,
+        options.stage_offsets, options.stage_names,
+    )
+}
+
+fn write_cache(cache_path: &Path, header: &str, context: &InitContext) -> Result<(), InitError> {
+    let mut cache = String::new();
+    writeln!(cache, "{}", header).unwrap();
+    for (file, module, cfgs) in context.module_cfgs.iter() {
+        writeln!(cache, "mod\t{}\t{}\t{}", file, module, cfgs.join("\t")).unwrap();
+    }
+    for f in context.functions.iter() {
+        let group = f.group.as_deref().unwrap_or_default();
+        let guard = f.guard.as_deref().unwrap_or_default();
+        let cleanup = f.cleanup.as_deref().unwrap_or_default();
+        // Descriptions are free text, they are quoted so that tabs and line breaks are escaped.
+        let description = f.description.as_ref().map(|description| format!("{:?}", description)).unwrap_or_default();
+        let slot = f.slot.as_deref().unwrap_or_default();
+        let timeout_ms = f.timeout_ms.map(|timeout_ms| timeout_ms.to_string()).unwrap_or_default();
+        write!(
+            cache, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            f.file, f.scanned_from, f.line, f.stage, f.returns_value, f.returns_result, f.retries, f.is_async, group, guard, cleanup, description, f.first, slot, f.deinit, timeout_ms,
+            f.after.join(","), f.before.join(","), f.module, f.name, f.call,
+        ).unwrap();
+        for cfg in f.cfgs.iter() {
+            write!(cache, "\t{}", cfg).unwrap();
+        }
+        writeln!(cache).unwrap();
+    }
+    std::fs::write(cache_path, cache).map_err(|error| InitError::Io { path: cache_path.to_path_buf(), error })
+}
+
+/// Parses the arguments of the `#[init(...)]` attribute into a map.
+/// Only the tokens inside the parentheses are used, a bare `#[init]` results in an empty map.
+/// The arguments are split on the token level, so line breaks, a trailing comma and string values containing `,` or `=` are handled.
+/// Keys listed in `aliases` are renamed to the key they stand for.
+fn attr_to_map(attr: &syn::Attribute, aliases: &[(String, String)]) -> Result<std::collections::HashMap<String, String>, String> {
+    let mut map = std::collections::HashMap::new();
+    let tokens: Vec<proc_macro2::TokenTree> = match attr.tokens.clone().into_iter().next() {
+        Some(proc_macro2::TokenTree::Group(group)) => group.stream().into_iter().collect(),
+        _ => Vec::new(),
+    };
+    let is_punct = |token: &proc_macro2::TokenTree, c: char| matches!(token, proc_macro2::TokenTree::Punct(p) if p.as_char() == c);
+    for argument in tokens.split(|token| is_punct(token, ',')) {
+        if argument.is_empty() {
+            continue;
+        }
+        let (key, value) = match argument {
+            [proc_macro2::TokenTree::Ident(key), eq, value @ ..] if is_punct(eq, '=') && !value.is_empty() => (key.to_string(), value),
+            [proc_macro2::TokenTree::Ident(_), ..] => return Err("Failed to parse attribute: no value".to_string()),
+            _ => return Err("Failed to parse attribute: no key".to_string()),
+        };
+        let value = value.iter().cloned().collect::<proc_macro2::TokenStream>().to_string();
+        let key = aliases.iter()
+            .find(|(alias, _)| *alias == key)
+            .map_or(key, |(_, target)| target.clone());
+        let aliased = aliases.iter().any(|(_, target)| *target == key);
+        if map.insert(key.clone(), value).is_some() && aliased {
+            return Err(format!("Failed to parse attribute: {} is set both directly and through an alias", key));
+        }
+    }
+    Ok(map)
+}
+
+/// Returns the content of a string literal attribute value, e.g. `debug` for `"debug"`.
+fn string_value(value: &str) -> Option<String> {
+    syn::parse_str::<syn::LitStr>(value).ok().map(|lit| lit.value())
+}
+
+/// Returns a hint for a misspelled attribute key, e.g. `stge` when `stage` is missing.
+/// The hint is empty if no unknown key is close enough to `missing`.
+fn suggest_key(map: &std::collections::HashMap<String, String>, missing: &str) -> String {
+    let typo = map.keys()
+        .filter(|key| !KNOWN_KEYS.contains(&key.as_str()))
+        .filter(|key| {
+            let closest = KNOWN_KEYS.iter().min_by_key(|known| edit_distance(key, known));
+            closest == Some(&missing) && edit_distance(key, missing) <= 2
+        })
+        .min_by_key(|key| edit_distance(key, missing));
+    match typo {
+        Some(typo) => format!(" Did you mean `{}` instead of `{}`?", missing, typo),
+        None => String::new(),
+    }
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Scans the file at `path`, whose items belong to the module `prefix`.
+/// `module_dir` is the directory of its child modules. It is only set when following the module tree, see [`InitOptions::follow_module_tree`].
+fn scan_file(context: &mut InitContext, options: &InitOptions, path: &str, prefix: &str, module_dir: Option<&Path>) -> Result<(), InitError> {
+    let file_content = std::fs::read_to_string(path).map_err(|error| InitError::Io { path: PathBuf::from(path), error })?;
+    let parse_error = |error| InitError::Parse { path: PathBuf::from(path), error };
+    let stream = proc_macro2::TokenStream::from_str(&file_content).map_err(|error| parse_error(error.into()))?;
+    let ast: syn::File = syn::parse2::<syn::File>(stream).map_err(parse_error)?;
+    context.scanned_files.push(path.to_string());
+    let start = context.functions.len();
+    scan_items(context, options, &ast.items, path, prefix, module_dir)?;
+    // Inner attributes like `#![cfg(feature = "metrics")]` apply to the whole module, including its submodules.
+    // Without the module tree the files of the submodules are scanned on their own, so the predicates are applied by `prepare`.
+    let cfgs = cfg_predicates(&ast.attrs);
+    if module_dir.is_none() && !cfgs.is_empty() {
+        context.module_cfgs.push((path.to_string(), prefix.to_string(), cfgs));
+        return Ok(());
+    }
+    for function in context.functions[start..].iter_mut() {
+        function.cfgs.extend(cfgs.iter().cloned());
+    }
+    Ok(())
+}
+
+fn scan_items(context: &mut InitContext, options: &InitOptions, items: &[Item], path: &str, prefix: &str, module_dir: Option<&Path>) -> Result<(), InitError> {
+    if !context.scanned_modules.iter().any(|(module, _)| module == prefix) {
+        context.scanned_modules.push((prefix.to_string(), path.to_string()));
+    }
+    for item in items {
+        match item {
+            Item::Fn(f) => {
+                scan_function(context, options, f, path, prefix)?;
+                scan_deinit_function(context, options, f, path, prefix)?;
+            }
+            Item::Macro(m) if m.mac.path.is_ident("include") => {
+                if let Some(included) = include_path(&m.mac, path) {
+                    scan_file(context, options, &included, prefix, module_dir)?;
+                }
+            }
+            Item::Mod(m) => {
+                let start = context.functions.len();
+                match (module_dir, &m.content) {
+                    (Some(module_dir), _) => scan_module(context, options, m, path, prefix, module_dir)?,
+                    // Without the module tree, module files are found by walking the directory, but inline modules only here.
+                    (None, Some((_, items))) => scan_items(context, options, items, path, &format!("{}::{}", prefix, m.ident), None)?,
+                    (None, None) => {}
+                }
+                let cfgs = cfg_predicates(&m.attrs);
+                if module_dir.is_none() && m.content.is_none() && !cfgs.is_empty() {
+                    // The file of the module is scanned on its own, so the predicates are applied by `prepare`.
+                    context.module_cfgs.push((path.to_string(), format!("{}::{}", prefix, m.ident), cfgs));
+                    continue;
+                }
+                for function in context.functions[start..].iter_mut() {
+                    function.cfgs.extend(cfgs.iter().cloned());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Scans the files reachable through `mod` declarations, starting from `lib.rs` and `main.rs` in `source_dir`.
+/// With [`InitOptions::skip_root`] the modules declared in the roots are still followed, only the functions defined in them are left out.
+fn scan_module_tree(context: &mut InitContext, options: &InitOptions, source_dir: &str) -> Result<(), InitError> {
+    for root in ["lib.rs", "main.rs"] {
+        let path = Path::new(source_dir).join(root);
+        if path.is_file() {
+            let path = path_to_str(&path)?;
+            scan_file(context, options, path, "crate", Some(Path::new(source_dir)))?;
+            if options.skip_root {
+                context.functions.retain(|f| f.file != path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Follows the module `m` declared in the file at `path`, either inline or in its own file.
+/// Module files are resolved like rustc does: `#[path = "..."]` relative to the declaring file, otherwise `name.rs` or `name/mod.rs` in `module_dir`.
+fn scan_module(context: &mut InitContext, options: &InitOptions, m: &syn::ItemMod, path: &str, prefix: &str, module_dir: &Path) -> Result<(), InitError> {
+    let name = m.ident.to_string();
+    let prefix = format!("{}::{}", prefix, name);
+    if let Some((_, items)) = &m.content {
+        return scan_items(context, options, items, path, &prefix, Some(&module_dir.join(&name)));
+    }
+    let path_attr = m.attrs.iter()
+        .filter(|attr| attr.path.is_ident("path"))
+        .find_map(|attr| match attr.parse_meta() {
+            Ok(syn::Meta::NameValue(syn::MetaNameValue { lit: syn::Lit::Str(lit), .. })) => Some(lit.value()),
+            _ => None,
+        });
+    let (file, child_dir) = match path_attr {
+        Some(path_attr) => {
+            let file = Path::new(path).parent().unwrap_or(Path::new("")).join(path_attr);
+            let child_dir = file.parent().unwrap_or(Path::new("")).to_path_buf();
+            (file, child_dir)
+        }
+        None => {
+            let file = module_dir.join(format!("{}.rs", name));
+            let file = if file.is_file() { file } else { module_dir.join(&name).join("mod.rs") };
+            (file, module_dir.join(&name))
+        }
+    };
+    if !file.is_file() {
+        context.warnings.push(format!("{}: file of module {} not found", path, prefix));
+        return Ok(());
+    }
+    scan_file(context, options, path_to_str(&file)?, &prefix, Some(&child_dir))
+}
+
+/// Fails if an init function is defined in a file that is not reachable from the module tree, see [`InitOptions::strict_module_tree`].
+fn check_reachable(context: &InitContext, options: &InitOptions, source_dir: &str) -> Result<(), InitError> {
+    let mut all = InitContext { exclude_fn_patterns: context.exclude_fn_patterns.clone(), ..InitContext::default() };
+    scan_dir(&mut all, options, source_dir, source_dir)?;
+    let unreachable: Vec<(String, PathBuf)> = all.functions.into_iter()
+        .filter(|f| !context.scanned_files.iter().any(|file| Path::new(file) == Path::new(&f.file)))
+        .map(|f| (f.call, PathBuf::from(f.file)))
+        .collect();
+    if unreachable.is_empty() {
+        Ok(())
+    } else {
+        Err(InitError::Unreachable(unreachable))
+    }
+}
+
+/// Matches both `#[init]` and qualified forms like `#[rs_init_macro::init]` by the last path segment.
+fn is_init_attribute(attr: &syn::Attribute, options: &InitOptions) -> bool {
+    attr.path.segments.last().is_some_and(|segment| segment.ident == options.attribute_name)
+}
+
+fn scan_function(context: &mut InitContext, options: &InitOptions, f: &syn::ItemFn, path: &str, prefix: &str) -> Result<(), InitError> {
+    let Some(attr) = f.attrs.iter().find(|a| is_init_attribute(a, options)) else {
+        return Ok(());
+    };
+    let name = f.sig.ident.to_string();
+    if context.exclude_fn_patterns.iter().any(|pattern| pattern.matches(&name)) {
+        return Ok(());
+    }
+    let invalid_attribute = |message: String| InitError::InvalidAttribute {
+        path: PathBuf::from(path),
+        function: name.clone(),
+        message,
+    };
+    if f.attrs.iter().filter(|a| is_init_attribute(a, options)).count() > 1 {
+        return Err(invalid_attribute("Function has more than one #[init] attribute, so its stage is ambiguous.".to_string()));
+    }
+    let map = attr_to_map(attr, &options.key_aliases).map_err(|message| invalid_attribute(message.to_string()))?;
+    let call_code = match map.get("call") {
+        Some(call) => match string_value(call) {
+            Some(call) if syn::parse_str::<syn::Path>(&call).is_ok() => call,
+            _ => return Err(invalid_attribute("Call parameter should be a string with the path of the function to call.".to_string())),
+        },
+        None => format!("{prefix}::{name}"),
+    };
+    let stage = map.get("stage")
+        .ok_or_else(|| invalid_attribute(format!("No stage parameter defined. It should be an integer.{}", suggest_key(&map, "stage"))))?;
+    let stages = match syn::parse_str::<syn::ExprIf>(stage) {
+        Ok(stage) => conditional_stages(&stage, Vec::new()).map_err(|message| invalid_attribute(message.to_string()))?,
+        Err(_) => vec![(Vec::new(), stage.clone())],
+    };
+    let offset: i64 = options.stage_offsets.iter()
+        .filter(|(prefix, _)| Path::new(path).starts_with(prefix))
+        .map(|(_, offset)| offset)
+        .sum();
+    let stages = stages.into_iter()
+        .map(|(conditions, stage)| Ok((conditions, resolve_stage(&stage, options).map_err(&invalid_attribute)? + offset)))
+        .collect::<Result<Vec<_>, InitError>>()?;
+    // Lifetime parameters are inferred at the call site, type and const parameters cannot be.
+    if f.sig.generics.params.iter().any(|param| !matches!(param, syn::GenericParam::Lifetime(_))) {
+        return Err(invalid_attribute("Init functions cannot have type or const parameters.".to_string()));
+    }
+    if options.pass_name && !takes_name(&f.sig) {
+        return Err(invalid_attribute("Init functions should take their name as a single &'static str parameter.".to_string()));
+    }
+    let mut cfgs = cfg_predicates(&f.attrs);
+    if let Some(profile) = map.get("profile") {
+        match string_value(profile).as_deref() {
+            Some("debug") => cfgs.push("debug_assertions".to_string()),
+            Some("release") => cfgs.push("not(debug_assertions)".to_string()),
+            _ => return Err(invalid_attribute("Profile parameter should be \"debug\" or \"release\".".to_string())),
+        }
+    }
+    let retries = match map.get("retries") {
+        Some(retries) => retries.parse::<u32>()
+            .map_err(|_| invalid_attribute("Retries parameter should be a non-negative integer.".to_string()))?,
+        None => 0,
+    };
+    let group = match map.get("group") {
+        Some(group) => match string_value(group) {
+            Some(group) if !group.is_empty() => Some(group),
+            _ => return Err(invalid_attribute("Group parameter should be a non-empty string.".to_string())),
+        },
+        None => None,
+    };
+    let guard = match map.get("guard") {
+        Some(guard) => match string_value(guard) {
+            Some(guard) if syn::parse_str::<syn::Path>(&guard).is_ok() => Some(guard),
+            _ => return Err(invalid_attribute("Guard parameter should be a string with the path of a function returning bool.".to_string())),
+        },
+        None => None,
+    };
+    let cleanup = match map.get("cleanup") {
+        Some(cleanup) => match string_value(cleanup) {
+            Some(cleanup) if cleanup.contains("::") && syn::parse_str::<syn::Path>(&cleanup).is_ok() => Some(cleanup),
+            Some(cleanup) if syn::parse_str::<syn::Ident>(&cleanup).is_ok() => Some(format!("{prefix}::{cleanup}")),
+            _ => return Err(invalid_attribute("Cleanup parameter should be a string with the name or the path of a function.".to_string())),
+        },
+        None => None,
+    };
+    let description = match map.get("description") {
+        Some(description) => match string_value(description) {
+            Some(description) => Some(description),
+            None => return Err(invalid_attribute("Description parameter should be a string.".to_string())),
+        },
+        None => None,
+    };
+    let first = match map.get("first").map(|first| first.as_str()) {
+        Some("true") => true,
+        Some("false") | None => false,
+        Some(_) => return Err(invalid_attribute("First parameter should be true or false.".to_string())),
+    };
+    let slot = match map.get("slot") {
+        Some(slot) => match string_value(slot) {
+            Some(slot) if !slot.is_empty() => Some(slot),
+            _ => return Err(invalid_attribute("Slot parameter should be a non-empty string.".to_string())),
+        },
+        None => None,
+    };
+    let timeout_ms = match map.get("timeout_ms") {
+        Some(timeout_ms) => Some(timeout_ms.parse::<u64>()
+            .map_err(|_| invalid_attribute("Timeout parameter should be a non-negative integer of milliseconds.".to_string()))?),
+        None => None,
+    };
+    if timeout_ms.is_some() && !is_async(&f.sig) {
+        return Err(invalid_attribute("Timeouts can only be used with async functions.".to_string()));
+    }
+    let dependency_error = |key: &str| invalid_attribute(format!(
+        "{} parameter should be a string or an array of strings with paths of init functions.", key,
+    ));
+    let after = match map.get("after") {
+        Some(after) => path_list(after).ok_or_else(|| dependency_error("After"))?,
+        None => Vec::new(),
+    };
+    let before = match map.get("before") {
+        Some(before) => path_list(before).ok_or_else(|| dependency_error("Before"))?,
+        None => Vec::new(),
+    };
+    if retries > 0 && !returns_result(&f.sig) {
+        return Err(invalid_attribute("Retries can only be used with functions returning a Result.".to_string()));
+    }
+
+    for (conditions, stage) in stages {
+        context.functions.push(InitFunction {
+            call: call_code.clone(),
+            module: prefix.to_string(),
+            name: name.clone(),
+            stage,
+            returns_value: returns_value(&f.sig),
+            returns_result: returns_result(&f.sig),
+            retries,
+            is_async: is_async(&f.sig),
+            group: group.clone(),
+            guard: guard.clone(),
+            cleanup: cleanup.clone(),
+            description: description.clone(),
+            first,
+            slot: slot.clone(),
+            deinit: false,
+            timeout_ms,
+            after: after.clone(),
+            before: before.clone(),
+            file: path.to_string(),
+            scanned_from: path.to_string(),
+            line: f.sig.ident.span().start().line,
+            cfgs: cfgs.iter().cloned().chain(conditions).collect(),
+        });
+    }
+    Ok(())
+}
+
+/// Parses a dependency like `"crate::db::init"` or `["crate::db::init", "crate::config::init"]`.
+fn path_list(value: &str) -> Option<Vec<String>> {
+    let paths = match syn::parse_str::<syn::ExprArray>(value) {
+        Ok(array) => array.elems.iter()
+            .map(|elem| match elem {
+                syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Str(lit), .. }) => Some(lit.value()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?,
+        Err(_) => vec![string_value(value)?],
+    };
+    paths.into_iter()
+        .map(|path| syn::parse_str::<syn::Path>(&path).ok().map(|_| path))
+        .collect()
+}
+
+/// Scans a teardown function marked with `#[deinit(stage = N)]`, see [`render_shutdown`].
+fn scan_deinit_function(context: &mut InitContext, options: &InitOptions, f: &syn::ItemFn, path: &str, prefix: &str) -> Result<(), InitError> {
+    let attribute_name = with_suffix(&options.attribute_name, "deinit");
+    let Some(attr) = f.attrs.iter().find(|a| a.path.segments.last().is_some_and(|segment| segment.ident == attribute_name)) else {
+        return Ok(());
+    };
+    let name = f.sig.ident.to_string();
+    let invalid_attribute = |message: String| InitError::InvalidAttribute {
+        path: PathBuf::from(path),
+        function: name.clone(),
+        message,
+    };
+    let map = attr_to_map(attr, &options.key_aliases).map_err(&invalid_attribute)?;
+    if let Some(key) = map.keys().find(|key| *key != "stage") {
+        return Err(invalid_attribute(format!("Unknown parameter {}, #[deinit] only takes a stage.", key)));
+    }
+    let stage = map.get("stage")
+        .ok_or_else(|| invalid_attribute("No stage parameter defined. It should be an integer.".to_string()))?;
+    let stage = resolve_stage(stage, options).map_err(&invalid_attribute)?;
+    if is_async(&f.sig) || !f.sig.inputs.is_empty() || !f.sig.generics.params.is_empty() {
+        return Err(invalid_attribute("Deinit functions should be synchronous and take no parameters.".to_string()));
+    }
+    context.functions.push(InitFunction {
+        call: format!("{prefix}::{name}"),
+        module: prefix.to_string(),
+        name: name.clone(),
+        stage,
+        returns_value: returns_value(&f.sig),
+        returns_result: returns_result(&f.sig),
+        retries: 0,
+        is_async: false,
+        group: None,
+        guard: None,
+        cleanup: None,
+        description: None,
+        first: false,
+        slot: None,
+        deinit: true,
+        timeout_ms: None,
+        after: Vec::new(),
+        before: Vec::new(),
+        file: path.to_string(),
+        scanned_from: path.to_string(),
+        line: f.sig.ident.span().start().line,
+        cfgs: cfg_predicates(&f.attrs),
+    });
+    Ok(())
+}
+
+/// Returns `true` if the only parameter of the function is a `&'static str`, see [`InitOptions::pass_name`].
+fn takes_name(sig: &syn::Signature) -> bool {
+    match sig.inputs.iter().collect::<Vec<_>>().as_slice() {
+        [syn::FnArg::Typed(arg)] => match arg.ty.as_ref() {
+            syn::Type::Reference(reference) => reference.mutability.is_none()
+                && reference.lifetime.as_ref().is_some_and(|lifetime| lifetime.ident == "static")
+                && matches!(reference.elem.as_ref(), syn::Type::Path(path) if path.qself.is_none() && path.path.is_ident("str")),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Resolves a stage written as an integer, as a path in [`InitOptions::phase_map`] or as a name in [`InitOptions::stage_names`].
+/// `stage` holds the tokens of the attribute value, whose spaces are removed unless it is a name.
+fn resolve_stage(stage: &str, options: &InitOptions) -> Result<i64, String> {
+    if let Some(name) = string_value(stage) {
+        return match options.stage_names.iter().position(|listed| *listed == name) {
+            Some(position) => Ok(position as i64),
+            None => Err(format!("Stage {} is not in the stage names.", name)),
+        };
+    }
+    let stage = &stage.replace(' ', "");
+    match stage.parse::<i64>() {
+        Ok(stage) => Ok(stage),
+        Err(_) if syn::parse_str::<syn::Path>(stage).is_ok() => options.phase_map.get(stage).copied()
+            .ok_or_else(|| format!("Stage {} is not in the phase map.", stage)),
+        Err(_) => Err("Stage parameter should be an integer.".to_string()),
+    }
+}
+
+/// Splits a stage like `if cfg!(feature = "fast") { 0 } else { 5 }` into the stages of every branch, with the `cfg`
+/// predicates selecting it. `negated` holds the predicates of the preceding branches, which must not match.
+fn conditional_stages(stage: &syn::ExprIf, mut negated: Vec<String>) -> Result<Vec<(Vec<String>, String)>, &'static str> {
+    const MESSAGE: &str = "Conditional stage should look like if cfg!(...) { 0 } else { 1 }.";
+    let branch_stage = |block: &syn::Block| match block.stmts.as_slice() {
+        [syn::Stmt::Expr(stage)] => Ok(quote::ToTokens::to_token_stream(stage).to_string()),
+        _ => Err(MESSAGE),
+    };
+    let predicate = match stage.cond.as_ref() {
+        syn::Expr::Macro(cond) if cond.mac.path.is_ident("cfg") => cond.mac.tokens.to_string(),
+        _ => return Err(MESSAGE),
+    };
+    let mut conditions = negated.clone();
+    conditions.push(predicate.clone());
+    let mut stages = vec![(conditions, branch_stage(&stage.then_branch)?)];
+    negated.push(format!("not({})", predicate));
+    match stage.else_branch.as_ref().map(|(_, branch)| branch.as_ref()) {
+        Some(syn::Expr::If(branch)) => stages.extend(conditional_stages(branch, negated)?),
+        Some(syn::Expr::Block(branch)) => stages.push((negated, branch_stage(&branch.block)?)),
+        _ => return Err(MESSAGE),
+    }
+    Ok(stages)
+}
+
+/// Resolves the file included by an `include!` invocation in the file at `path`.
+/// Relative paths are resolved from the directory of the including file, like `include!` does.
+/// Returns `None` if the argument cannot be evaluated, see [`const_string`], or if the file does not exist yet,
+/// like the generated `init.rs` itself before the first build.
+/// Files generated by rs-init are skipped as well. They never define init functions, and an outdated one may not parse.
+fn include_path(mac: &syn::Macro, path: &str) -> Option<String> {
+    let included = const_string(&mac.parse_body::<syn::Expr>().ok()?)?;
+    let included = match Path::new(path).parent() {
+        Some(dir) => dir.join(included),
+        None => PathBuf::from(included),
+    };
+    if !included.is_file() {
+        return None;
+    }
+    if std::fs::read_to_string(&included).is_ok_and(|content| content.starts_with("// Generated by rs-init")) {
+        return None;
+    }
+    included.to_str().map(|included| included.to_string())
+}
+
+/// Evaluates a string literal, `concat!(...)` of such expressions or `env!("VAR")`, as it is commonly used in `include!`.
+/// `env!` is evaluated with the environment of the build script, which includes `OUT_DIR`.
+fn const_string(expr: &syn::Expr) -> Option<String> {
+    match expr {
+        syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Str(lit), .. }) => Some(lit.value()),
+        syn::Expr::Macro(m) if m.mac.path.is_ident("concat") => {
+            let parser = syn::punctuated::Punctuated::<syn::Expr, syn::Token![,]>::parse_terminated;
+            let args = m.mac.parse_body_with(parser).ok()?;
+            args.iter().map(const_string).collect()
+        }
+        syn::Expr::Macro(m) if m.mac.path.is_ident("env") => {
+            let var = m.mac.parse_body::<syn::LitStr>().ok()?;
+            std::env::var(var.value()).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Returns the predicates of all `#[cfg(...)]` attributes, e.g. `target_os = "linux"`.
+/// The tokens are kept verbatim, so any predicate valid in `#[cfg]` can be copied to the generated code.
+fn cfg_predicates(attrs: &[syn::Attribute]) -> Vec<String> {
+    attrs.iter()
+        .filter(|attr| attr.path.is_ident("cfg"))
+        .filter_map(|attr| match attr.tokens.clone().into_iter().next() {
+            Some(proc_macro2::TokenTree::Group(group)) => Some(group.stream().to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns `true` if the function returns something other than `()`.
+/// For functions returning `impl Future`, the output of the future is checked instead.
+/// Such calls are emitted as `let _ = ...;` so that `#[must_use]` values do not trigger warnings in the generated code.
+fn returns_value(sig: &syn::Signature) -> bool {
+    match &sig.output {
+        syn::ReturnType::Default => false,
+        syn::ReturnType::Type(_, ty) => match future_bound(ty) {
+            Some(future) => future_output(future).is_none_or(|output| !is_unit(output)),
+            None => !is_unit(ty),
+        },
+    }
+}
+
+/// Returns `true` if the function returns a `Result`, or a future resolving to one.
+/// Any type named `Result` is accepted, e.g. `std::io::Result<()>` or `anyhow::Result<()>`.
+fn returns_result(sig: &syn::Signature) -> bool {
+    let syn::ReturnType::Type(_, ty) = &sig.output else {
+        return false;
+    };
+    let ty = match future_bound(ty) {
+        Some(future) => match future_output(future) {
+            Some(output) => output,
+            None => return false,
+        },
+        None => ty.as_ref(),
+    };
+    matches!(ty, syn::Type::Path(path) if path.path.segments.last().is_some_and(|segment| segment.ident == "Result"))
+}
+
+fn is_unit(ty: &syn::Type) -> bool {
+    matches!(ty, syn::Type::Tuple(t) if t.elems.is_empty())
+}
+
+/// Returns `true` for `async fn` and for functions returning `impl Future`, which have to be awaited.
+fn is_async(sig: &syn::Signature) -> bool {
+    sig.asyncness.is_some() || matches!(&sig.output, syn::ReturnType::Type(_, ty) if future_bound(ty).is_some())
+}
+
+/// Returns the `Future` bound of an `impl Future<Output = T>` type.
+fn future_bound(ty: &syn::Type) -> Option<&syn::PathSegment> {
+    let syn::Type::ImplTrait(impl_trait) = ty else {
+        return None;
+    };
+    impl_trait.bounds.iter().find_map(|bound| match bound {
+        syn::TypeParamBound::Trait(bound) => bound.path.segments.last().filter(|segment| segment.ident == "Future"),
+        _ => None,
+    })
+}
+
+/// Returns `T` of `Future<Output = T>`.
+fn future_output(future: &syn::PathSegment) -> Option<&syn::Type> {
+    let syn::PathArguments::AngleBracketed(arguments) = &future.arguments else {
+        return None;
+    };
+    arguments.args.iter().find_map(|argument| match argument {
+        syn::GenericArgument::Binding(binding) if binding.ident == "Output" => Some(&binding.ty),
+        _ => None,
+    })
 }
@@ -0,0 +1,18 @@
+/// The init functions in call order, as returned by the `init_plan` function generated with [`InitOptions::init_plan`](crate::InitOptions::init_plan).
+/// It can be serialized with serde, e.g. to review the plan with other tools before a deployment.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct InitPlan {
+    pub entries: Vec<InitPlanEntry>,
+}
+
+/// A single init function of an [`InitPlan`].
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct InitPlanEntry {
+    /// Path of the init function, e.g. `crate::db::init`.
+    pub path: &'static str,
+    pub stage: i64,
+    /// Group set with `#[init(group = "...")]`.
+    pub group: Option<&'static str>,
+    /// Description set with `#[init(description = "...")]`.
+    pub description: Option<&'static str>,
+}
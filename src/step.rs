@@ -0,0 +1,10 @@
+/// A single init function, as returned by the `generated_init_registry` function generated with [`InitOptions::registry`](crate::InitOptions::registry).
+/// It allows inspecting, reordering or filtering the init functions at runtime before running them.
+pub trait InitStep {
+    /// Calls the init function.
+    fn run(&self);
+    /// Returns the stage of the init function.
+    fn stage(&self) -> i64;
+    /// Returns the path of the init function, e.g. `crate::db::init`.
+    fn name(&self) -> &'static str;
+}
@@ -0,0 +1,1577 @@
+use super::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Source tree written to a temporary directory, which is removed when the fixture is dropped.
+struct Fixture {
+    root: PathBuf,
+}
+
+impl Fixture {
+    /// Writes the `files`, given as a path relative to the fixture root and the content, e.g. `("src/lib.rs", "...")`.
+    fn new(files: &[(&str, &str)]) -> Fixture {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("rs-init-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        let root = std::env::temp_dir().join(name);
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        let fixture = Fixture { root };
+        for (path, content) in files {
+            fixture.write(path, content);
+        }
+        fixture
+    }
+
+    fn write(&self, path: &str, content: &str) {
+        let path = self.root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    fn path(&self, path: &str) -> String {
+        self.root.join(path).to_str().unwrap().to_string()
+    }
+
+    fn src(&self) -> String {
+        self.path("src")
+    }
+
+    fn render(&self, options: &InitOptions) -> String {
+        render_init_with(&self.src(), options).unwrap()
+    }
+
+    fn error(&self, options: &InitOptions) -> InitError {
+        match render_init_with(&self.src(), options) {
+            Ok(code) => panic!("expected an error, generated:\n{}", code),
+            Err(error) => error,
+        }
+    }
+
+    /// Returns the paths of the init functions in call order.
+    fn calls(&self, options: &InitOptions) -> Vec<String> {
+        collect(&self.src(), options, None).unwrap().functions.into_iter().map(|f| f.call).collect()
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Serializes the tests reading or writing environment variables like `OUT_DIR` and `RS_INIT_CHANGED`.
+fn env_lock() -> std::sync::MutexGuard<'static, ()> {
+    static ENV: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    ENV.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// Scans `source_dir` like [`generate_init_function`] with the cache at `cache_path`, and `RS_INIT_CHANGED` set to
+/// `changed` if it is given.
+fn collect_changed(source_dir: &str, options: &InitOptions, cache_path: &str, changed: Option<&str>) -> InitContext {
+    let _env = env_lock();
+    match changed {
+        Some(changed) => std::env::set_var(CHANGED_FILES_VAR, changed),
+        None => std::env::remove_var(CHANGED_FILES_VAR),
+    }
+    let context = collect(source_dir, options, Some(Path::new(cache_path)));
+    std::env::remove_var(CHANGED_FILES_VAR);
+    context.unwrap()
+}
+
+/// Compiles `source` as a binary with the given edition and runs it, returning what it printed to stdout and stderr.
+fn run_program(source: &str, edition: &str) -> (String, String) {
+    let fixture = Fixture::new(&[("main.rs", source)]);
+    let binary = fixture.path("main");
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let output = std::process::Command::new(rustc)
+        .args(["--edition", edition, "-o", &binary, &fixture.path("main.rs")])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}\n{}", source, String::from_utf8_lossy(&output.stderr));
+    let output = std::process::Command::new(&binary).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap())
+}
+
+/// Asserts that `parts` appear in `code` in the given order.
+#[track_caller]
+fn assert_in_order(code: &str, parts: &[&str]) {
+    let mut position = 0;
+    for part in parts {
+        match code[position..].find(part) {
+            Some(found) => position += found + part.len(),
+            None => panic!("{:?} not found in order {:?} in:\n{}", part, parts, code),
+        }
+    }
+}
+
+#[test]
+fn value_returning_init_result_is_ignored() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\n#[must_use]\nfn a() -> u32 { 1 }\n#[init(stage = 1)]\nfn b() {}\n")]);
+    let code = fixture.render(&InitOptions::default());
+    assert_in_order(&code, &["\tlet _ = crate::a();", "\tcrate::b();"]);
+}
+
+#[test]
+fn changed_files_are_scanned_again_from_cache() {
+    let fixture = Fixture::new(&[
+        ("src/m/a.rs", "#[init(stage = 0)]\nfn init() {}\n"),
+        ("src/m/b.rs", "#[init(stage = 1)]\nfn init() {}\n"),
+    ]);
+    let cache = fixture.path("cache.txt");
+    collect_changed(&fixture.src(), &InitOptions::default(), &cache, None);
+
+    // Only the listed file is scanned again, the functions of the other files are taken from the cache.
+    fixture.write("src/m/a.rs", "#[init(stage = 0)]\nfn renamed() {}\n");
+    fixture.write("src/m/b.rs", "#[init(stage = 1)]\nfn init() {}\n#[init(stage = 2)]\nfn late() {}\n");
+    let context = collect_changed(&fixture.src(), &InitOptions::default(), &cache, Some(&fixture.path("src/m/b.rs")));
+    let calls: Vec<&str> = context.functions.iter().map(|f| f.call.as_str()).collect();
+    assert_eq!(calls, ["crate::m::a::init", "crate::m::b::init", "crate::m::b::late"]);
+}
+
+#[test]
+fn select_stages_dispatches_on_stage() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 1)]\nfn b() {}\n#[init(stage = 2)]\nfn c() {}\n")]);
+    let code = fixture.render(&InitOptions { select_stages: true, ..InitOptions::default() });
+    assert_in_order(&code, &[
+        "pub fn generated_init(stages: &[i64]) {",
+        "\tif stages.contains(&0) {\n\t\tcrate::a();\n\t}",
+        "\tif stages.contains(&1) {\n\t\tcrate::b();\n\t}",
+        "\tif stages.contains(&2) {\n\t\tcrate::c();\n\t}",
+    ]);
+}
+
+#[test]
+fn misspelled_stage_key_is_suggested() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stge = 1)]\nfn a() {}\n")]);
+    match fixture.error(&InitOptions::default()) {
+        InitError::InvalidAttribute { message, .. } => assert!(message.contains("Did you mean `stage` instead of `stge`?"), "{}", message),
+        error => panic!("unexpected error {:?}", error),
+    }
+}
+
+#[test]
+fn render_init_does_not_need_out_dir() {
+    let _env = env_lock();
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n")]);
+    let out_dir = std::env::var_os("OUT_DIR");
+    std::env::remove_var("OUT_DIR");
+    let code = render_init(&fixture.src());
+    if let Some(out_dir) = out_dir {
+        std::env::set_var("OUT_DIR", out_dir);
+    }
+    assert!(code.unwrap().contains("pub fn generated_init() {\n\tcrate::a();\n}"));
+}
+
+#[test]
+fn path_rewrites_replace_call_prefix() {
+    let fixture = Fixture::new(&[("src/internal/setup.rs", "#[init(stage = 0)]\nfn init() {}\n")]);
+    let options = InitOptions {
+        path_rewrites: vec![("crate::internal::setup::init".to_string(), "crate::public_init".to_string())],
+        ..InitOptions::default()
+    };
+    let code = fixture.render(&options);
+    assert!(code.contains("\tcrate::public_init();"), "{}", code);
+    assert!(!code.contains("crate::internal"), "{}", code);
+}
+
+#[test]
+fn split_by_stage_writes_stage_files() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 1)]\nfn b() {}\n")]);
+    let out_dir = fixture.path("out");
+    std::fs::create_dir(&out_dir).unwrap();
+    let options = InitOptions { split_by_stage: true, out_dir: Some(out_dir.into()), ..InitOptions::default() };
+    try_generate_init_function_with(&fixture.src(), &options).unwrap();
+    let read = |file: &str| std::fs::read_to_string(fixture.path(&format!("out/{}", file))).unwrap();
+    assert_in_order(&read("init_stage_0.rs"), &["pub fn generated_init_stage_0() {", "\tcrate::a();"]);
+    assert_in_order(&read("init_stage_1.rs"), &["pub fn generated_init_stage_1() {", "\tcrate::b();"]);
+    assert_in_order(&read("init.rs"), &[
+        "include!(\"init_stage_0.rs\");",
+        "include!(\"init_stage_1.rs\");",
+        "pub fn generated_init() {",
+        "\tgenerated_init_stage_0();",
+        "\tgenerated_init_stage_1();",
+    ]);
+}
+
+#[test]
+fn validate_matches_generation() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n")]);
+    assert!(validate(&fixture.src()).is_ok());
+    // A missing stage, and a function called through the same path as a function in another file.
+    let missing_stage = [("src/lib.rs", "#[init(priority = 1)]\nfn a() {}\n"), ("src/main.rs", "")];
+    let duplicate = [("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n"), ("src/main.rs", "#[init(stage = 1)]\nfn a() {}\n")];
+    for files in [missing_stage, duplicate] {
+        for (path, source) in files {
+            fixture.write(path, source);
+        }
+        let validated = validate(&fixture.src()).unwrap_err();
+        assert_eq!(validated.to_string(), render_init(&fixture.src()).unwrap_err().to_string());
+    }
+}
+
+#[test]
+fn descending_reverses_stages() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 1)]\nfn b() {}\n#[init(stage = 0)]\nfn a() {}\n#[init(stage = 2)]\nfn c() {}\n#[init(stage = 2)]\nfn d() {}\n")]);
+    let options = InitOptions { descending: true, ..InitOptions::default() };
+    assert_eq!(fixture.calls(&options), ["crate::c", "crate::d", "crate::b", "crate::a"]);
+}
+
+#[test]
+fn target_cfgs_gate_calls() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\n#[cfg(target_os = \"linux\")]\nfn a() {}\n#[init(stage = 1)]\n#[cfg(all(target_arch = \"x86_64\", feature = \"fast\"))]\nfn b() {}\n")]);
+    let code = fixture.render(&InitOptions::default());
+    assert_in_order(&code, &[
+        "\t#[cfg(target_os = \"linux\")]\n\tcrate::a();",
+        "\t#[cfg(all (target_arch = \"x86_64\" , feature = \"fast\"))]\n\tcrate::b();",
+    ]);
+}
+
+#[test]
+fn join_stages_runs_stage_concurrently() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nasync fn a() {}\n#[init(stage = 0)]\nasync fn b() {}\n#[init(stage = 1)]\nasync fn c() {}\n")]);
+    let code = fixture.render(&InitOptions { asynchronous: true, join_stages: true, ..InitOptions::default() });
+    assert_in_order(&code, &[
+        "pub async fn generated_init() {",
+        "\t\tstage.push(Box::pin(async { crate::a().await; }));",
+        "\t\tstage.push(Box::pin(async { crate::b().await; }));",
+        "\t\t::futures::future::join_all(stage).await;",
+        "\tcrate::c().await;",
+    ]);
+}
+
+#[test]
+fn header_states_version() {
+    let fixture = Fixture::new(&[("src/lib.rs", "")]);
+    let code = fixture.render(&InitOptions::default());
+    assert!(code.starts_with(&format!("// Generated by rs-init {}. Do not edit.\n", env!("CARGO_PKG_VERSION"))), "{}", code);
+    assert!(!code.contains("Generated at"));
+    assert!(fixture.render(&InitOptions { timestamp: true, ..InitOptions::default() }).contains("// Generated at "));
+}
+
+#[test]
+fn profile_gates_calls() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0, profile = \"debug\")]\nfn a() {}\n#[init(stage = 1, profile = \"release\")]\nfn b() {}\n")]);
+    let code = fixture.render(&InitOptions::default());
+    assert_in_order(&code, &["\t#[cfg(debug_assertions)]\n\tcrate::a();", "\t#[cfg(not(debug_assertions))]\n\tcrate::b();"]);
+}
+
+#[test]
+fn unrelated_attributes_around_init_are_ignored() {
+    let fixture = Fixture::new(&[("src/lib.rs", r#"
+        /// Sets up logging.
+        #[inline]
+        #[init(stage = 1)]
+        #[allow(dead_code)]
+        #[doc = "More docs."]
+        fn a() {}
+        #[must_use]
+        #[doc(hidden)]
+        #[init(stage = 0)]
+        fn b() {}
+    "#)]);
+    let code = fixture.render(&InitOptions::default());
+    assert_in_order(&code, &["pub fn generated_init() {\n\tcrate::b();\n\tcrate::a();\n}"]);
+}
+
+#[test]
+fn registry_wraps_each_function() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 2)]\nfn b() {}\n#[init(stage = 0)]\nfn a() {}\n")]);
+    let code = fixture.render(&InitOptions { registry: true, ..InitOptions::default() });
+    assert_in_order(&code, &[
+        "pub fn generated_init_registry() -> Vec<Box<dyn ::rs_init::InitStep>> {",
+        "\timpl ::rs_init::InitStep for Step0 {\n\t\tfn run(&self) {\n\t\t\tcrate::a();\n\t\t}\n\t\tfn stage(&self) -> i64 {\n\t\t\t0\n\t\t}",
+        "\timpl ::rs_init::InitStep for Step1 {\n\t\tfn run(&self) {\n\t\t\tcrate::b();\n\t\t}\n\t\tfn stage(&self) -> i64 {\n\t\t\t2\n\t\t}",
+        "\tsteps.push(Box::new(Step0));\n\tsteps.push(Box::new(Step1));\n\tsteps\n}",
+    ]);
+}
+
+#[test]
+fn impl_future_is_awaited() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() -> impl std::future::Future<Output = ()> { async {} }\n#[init(stage = 1)]\nfn b() -> impl Future<Output = ()> + Send { async {} }\n#[init(stage = 2)]\nfn c() {}\n")]);
+    let code = fixture.render(&InitOptions { asynchronous: true, ..InitOptions::default() });
+    assert_in_order(&code, &["\tcrate::a().await;", "\tcrate::b().await;", "\tcrate::c();"]);
+}
+
+#[test]
+fn indent_is_configurable() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 1)]\nfn b() {}\n")]);
+    let code = fixture.render(&InitOptions { indent: "    ".to_string(), select_stages: true, ..InitOptions::default() });
+    assert_in_order(&code, &["\n    if stages.contains(&0) {\n        crate::a();\n    }\n", "    if stages.contains(&1) {\n        crate::b();\n    }\n"]);
+    assert!(!code.contains('\t'));
+}
+
+#[test]
+fn group_by_module_first_keeps_modules_together() {
+    let fixture = Fixture::new(&[
+        ("src/m/a.rs", "#[init(stage = 2)]\nfn late() {}\n#[init(stage = 0)]\nfn early() {}\n"),
+        ("src/m/b.rs", "#[init(stage = 1)]\nfn late() {}\n#[init(stage = -1)]\nfn early() {}\n"),
+    ]);
+    assert_eq!(fixture.calls(&InitOptions::default()), ["crate::m::b::early", "crate::m::a::early", "crate::m::b::late", "crate::m::a::late"]);
+    let options = InitOptions { group_by_module_first: true, ..InitOptions::default() };
+    assert_eq!(fixture.calls(&options), ["crate::m::a::early", "crate::m::a::late", "crate::m::b::early", "crate::m::b::late"]);
+}
+
+#[test]
+fn included_file_functions_get_including_module_path() {
+    let fixture = Fixture::new(&[("src/lib.rs", "mod plugins;")]);
+    fixture.write("generated/stuff.rs", "#[init(stage = 0)]\nfn included() {}\n");
+    fixture.write("src/plugins.rs", &format!("include!({:?});\n", fixture.path("generated/stuff.rs")));
+    assert_eq!(fixture.calls(&InitOptions::default()), ["crate::plugins::included"]);
+    let options = InitOptions { follow_module_tree: true, ..InitOptions::default() };
+    assert_eq!(fixture.calls(&options), ["crate::plugins::included"]);
+}
+
+#[test]
+fn included_file_functions_are_not_duplicated_incrementally() {
+    let fixture = Fixture::new(&[]);
+    fixture.write("generated/stuff.rs", "#[init(stage = 0)]\nfn included() {}\n");
+    fixture.write("src/lib.rs", &format!("include!({:?});\n#[init(stage = 1)]\nfn own() {{}}\n", fixture.path("generated/stuff.rs")));
+    let cache = fixture.path("cache.txt");
+    let options = InitOptions::default();
+    let calls = |context: InitContext| context.functions.into_iter().map(|f| f.call).collect::<Vec<_>>();
+    assert_eq!(calls(collect_changed(&fixture.src(), &options, &cache, None)), ["crate::included", "crate::own"]);
+    for changed in ["src/lib.rs", "src/lib.rs", "generated/stuff.rs"] {
+        let context = collect_changed(&fixture.src(), &options, &cache, Some(&fixture.path(changed)));
+        assert_eq!(calls(context), ["crate::included", "crate::own"]);
+    }
+}
+
+#[test]
+fn include_skips_missing_and_generated_files() {
+    let fixture = Fixture::new(&[]);
+    fixture.write("out/init.rs", "// Generated by rs-init 0.1.0. Do not edit.\npub fn generated_init() {\n");
+    let include = |file: &str| format!("include!({:?});\n", fixture.path(file));
+    fixture.write("src/lib.rs", &format!("{}{}#[init(stage = 0)]\nfn own() {{}}\n", include("out/missing.rs"), include("out/init.rs")));
+    assert_eq!(fixture.calls(&InitOptions::default()), ["crate::own"]);
+}
+
+#[test]
+fn min_stage_skips_lower_stages() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 1)]\nfn b() {}\n#[init(stage = 2)]\nfn c() {}\n#[init(stage = 3)]\nfn d() {}\n")]);
+    assert_eq!(fixture.calls(&InitOptions { min_stage: Some(1), ..InitOptions::default() }), ["crate::b", "crate::c", "crate::d"]);
+    assert_eq!(fixture.calls(&InitOptions { min_stage: Some(1), max_stage: Some(2), ..InitOptions::default() }), ["crate::b", "crate::c"]);
+}
+
+#[test]
+fn sync_and_async_generates_both_functions() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 1)]\nfn b() {}\n")]);
+    let code = fixture.render(&InitOptions { sync_and_async: true, ..InitOptions::default() });
+    assert_in_order(&code, &[
+        "pub fn generated_init() {\n\tcrate::a();\n\tcrate::b();\n}",
+        "pub async fn generated_init_async() {\n\tcrate::a();\n\tcrate::b();\n}",
+    ]);
+    fixture.write("src/lib.rs", "#[init(stage = 0)]\nasync fn a() {}\n");
+    assert!(matches!(fixture.error(&InitOptions { sync_and_async: true, ..InitOptions::default() }), InitError::Incompatible { .. }));
+}
+
+#[test]
+fn order_override_sets_call_order() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 1)]\nfn b() {}\n#[init(stage = 2)]\nfn c() {}\n")]);
+    let order = |paths: &[&str]| InitOptions { order_override: paths.iter().map(|path| path.to_string()).collect(), ..InitOptions::default() };
+    assert_eq!(fixture.calls(&order(&["crate::c", "crate::a", "crate::b"])), ["crate::c", "crate::a", "crate::b"]);
+    let error = fixture.error(&order(&["crate::c", "crate::a"]));
+    assert!(error.to_string().contains("crate::b"), "{}", error);
+}
+
+#[test]
+fn verbose_reports_files_without_init_functions() {
+    let fixture = Fixture::new(&[("src/lib.rs", "mod a;\nmod empty;\n"), ("src/a.rs", "#[init(stage = 0)]\nfn init() {}\n"), ("src/empty.rs", "fn helper() {}\n")]);
+    let warnings = collect(&fixture.src(), &InitOptions { verbose: true, ..InitOptions::default() }, None).unwrap().warnings;
+    assert!(warnings.contains(&format!("{} contributed no init functions", fixture.path("src/empty.rs"))), "{:?}", warnings);
+    assert!(!warnings.iter().any(|warning| warning.contains("a.rs contributed")), "{:?}", warnings);
+    assert!(collect(&fixture.src(), &InitOptions::default(), None).unwrap().warnings.is_empty());
+}
+
+#[test]
+fn async_fallible_propagates_errors() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nasync fn a() -> Result<(), Error> { Ok(()) }\n#[init(stage = 1)]\nasync fn b() -> Result<(), Error> { Ok(()) }\n")]);
+    let code = fixture.render(&InitOptions { asynchronous: true, fallible: true, ..InitOptions::default() });
+    assert_in_order(&code, &["pub async fn generated_init() -> Result<(), ", "\tcrate::a().await?;", "\tcrate::b().await?;", "\tOk(())\n}"]);
+}
+
+#[test]
+fn retries_loop_on_error() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0, retries = 3)]\nfn a() -> Result<(), Error> { Ok(()) }\n#[init(stage = 1)]\nfn b() -> Result<(), Error> { Ok(()) }\n")]);
+    let code = fixture.render(&InitOptions { fallible: true, ..InitOptions::default() });
+    assert_in_order(&code, &[
+        "\t{ let mut attempt = 0; loop { match crate::a() { Ok(_) => break, Err(_) if attempt < 3 => attempt += 1, Err(error) => Err(error)? } } }",
+        "\tcrate::b()?;",
+    ]);
+    assert!(matches!(fixture.error(&InitOptions::default()), InitError::Incompatible { .. }));
+}
+
+#[test]
+fn log_macro_wraps_calls() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 1)]\nfn b() {}\n")]);
+    let code = fixture.render(&InitOptions { log_macro: Some("log::info".to_string()), ..InitOptions::default() });
+    assert_in_order(&code, &[
+        "\tlog::info!(\"starting crate::a\");\n\tcrate::a();\n\tlog::info!(\"finished crate::a\");",
+        "\tlog::info!(\"starting crate::b\");\n\tcrate::b();\n\tlog::info!(\"finished crate::b\");",
+    ]);
+    let options = InitOptions { log_macro: Some("tracing::debug".to_string()), log_start_format: "init {path}".to_string(), ..InitOptions::default() };
+    assert!(fixture.render(&options).contains("\ttracing::debug!(\"init crate::a\");\n\tcrate::a();"));
+}
+
+#[test]
+fn file_next_to_directory_module() {
+    let fixture = Fixture::new(&[
+        ("src/lib.rs", "mod foo;\n"),
+        ("src/foo.rs", "mod bar;\n#[init(stage = 0)]\nfn init() {}\n"),
+        ("src/foo/bar.rs", "#[init(stage = 1)]\nfn init() {}\n"),
+    ]);
+    let expected = ["crate::foo::init", "crate::foo::bar::init"];
+    assert_eq!(fixture.calls(&InitOptions::default()), expected);
+    assert_eq!(fixture.calls(&InitOptions { follow_module_tree: true, ..InitOptions::default() }), expected);
+}
+
+#[test]
+fn key_aliases_rename_keys() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(order = 1)]\nfn a() {}\n#[init(order = 0)]\nfn b() {}\n")]);
+    let options = InitOptions { key_aliases: vec![("order".to_string(), "stage".to_string())], ..InitOptions::default() };
+    assert_eq!(fixture.calls(&options), ["crate::b", "crate::a"]);
+    assert!(matches!(fixture.error(&InitOptions::default()), InitError::InvalidAttribute { .. }));
+}
+
+#[test]
+fn error_if_empty_emits_compile_error() {
+    let fixture = Fixture::new(&[("src/lib.rs", "fn helper() {}\n")]);
+    let code = fixture.render(&InitOptions { error_if_empty: true, ..InitOptions::default() });
+    assert!(code.contains("compile_error!(\"no #[init] functions found\");"), "{}", code);
+    assert!(!code.contains("generated_init"), "{}", code);
+    assert!(fixture.render(&InitOptions::default()).contains("pub fn generated_init() {\n}"));
+}
+
+#[test]
+fn glob_scans_matching_files_only() {
+    let fixture = Fixture::new(&[
+        ("src/lib.rs", "#[init(stage = 0)]\nfn root() {}\n"),
+        ("src/plugins_audio.rs", "#[init(stage = 1)]\nfn init() {}\n"),
+        ("src/net/plugins_http.rs", "#[init(stage = 0)]\nfn init() {}\n"),
+        ("src/net/client.rs", "#[init(stage = 0)]\nfn init() {}\n"),
+    ]);
+    let _env = env_lock();
+    // The module paths of the matched files are computed relative to `src` in the current directory.
+    let current_dir = std::env::current_dir().unwrap();
+    std::env::set_current_dir(&fixture.root).unwrap();
+    let context = collect_glob("src/**/plugins_*.rs", &InitOptions::default());
+    std::env::set_current_dir(current_dir).unwrap();
+    let calls: Vec<String> = context.unwrap().functions.into_iter().map(|f| f.call).collect();
+    assert_eq!(calls, ["crate::net::plugins_http::init", "crate::plugins_audio::init"]);
+}
+
+#[test]
+fn lifetime_generics_are_callable() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a<'a>() {}\n#[init(stage = 1)]\nfn b<'a, 'b: 'a>() {}\n")]);
+    assert!(fixture.render(&InitOptions::default()).contains("pub fn generated_init() {\n\tcrate::a();\n\tcrate::b();\n}"));
+    for generics in ["<T>", "<'a, T: Default>", "<const N: usize>"] {
+        fixture.write("src/lib.rs", &format!("#[init(stage = 0)]\nfn a{}() {{}}\n", generics));
+        assert!(matches!(fixture.error(&InitOptions::default()), InitError::InvalidAttribute { .. }), "{}", generics);
+    }
+}
+
+#[test]
+fn group_order_runs_groups_in_order() {
+    let fixture = Fixture::new(&[("src/lib.rs", r#"
+        #[init(stage = 0, group = "plugins")]
+        fn plugin_early() {}
+        #[init(stage = 2, group = "core")]
+        fn core_late() {}
+        #[init(stage = 1, group = "plugins")]
+        fn plugin_late() {}
+        #[init(stage = 1, group = "core")]
+        fn core_early() {}
+        #[init(stage = 0, group = "extra")]
+        fn extra() {}
+        #[init(stage = -1)]
+        fn ungrouped() {}
+    "#)]);
+    let options = InitOptions { group_order: vec!["core".to_string(), "plugins".to_string()], ..InitOptions::default() };
+    let expected = ["crate::core_early", "crate::core_late", "crate::plugin_early", "crate::plugin_late", "crate::extra", "crate::ungrouped"];
+    assert_eq!(fixture.calls(&options), expected);
+}
+
+#[test]
+fn skip_root_skips_main_rs() {
+    let fixture = Fixture::new(&[
+        ("src/main.rs", "mod net;\n#[init(stage = 0)]\nfn root() {}\n"),
+        ("src/net/mod.rs", "mod http;\n#[init(stage = 0)]\nfn init() {}\n"),
+        ("src/net/http.rs", "#[init(stage = 1)]\nfn init() {}\n"),
+    ]);
+    for follow_module_tree in [false, true] {
+        let options = InitOptions { skip_root: true, follow_module_tree, ..InitOptions::default() };
+        assert_eq!(fixture.calls(&options), ["crate::net::init", "crate::net::http::init"]);
+    }
+}
+
+#[test]
+fn relative_root_prefix_warns() {
+    let fixture = Fixture::new(&[("src/lib.rs", "mod db;\n"), ("src/db.rs", "#[init(stage = 0)]\nfn init() {}\n")]);
+    let options = InitOptions { root_prefix: "super".to_string(), ..InitOptions::default() };
+    let context = collect(&fixture.src(), &options, None).unwrap();
+    assert_eq!(context.functions[0].call, "super::db::init");
+    assert_eq!(context.warnings, ["root_prefix super is relative, the generated file has to be included in a module directly below the crate root"]);
+    let options = InitOptions { root_prefix: "crate::(".to_string(), ..InitOptions::default() };
+    assert!(matches!(fixture.error(&options), InitError::InvalidOptions(_)));
+}
+
+#[test]
+fn feature_gate_applies_to_generated_items() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n")]);
+    let code = fixture.render(&InitOptions { feature_gate: Some("init".to_string()), stage_map: true, ..InitOptions::default() });
+    assert_in_order(&code, &["#[cfg(feature = \"init\")]\npub fn generated_init() {", "#[cfg(feature = \"init\")]\npub fn generated_init_map()"]);
+}
+
+#[test]
+fn stage_map_buckets_functions_by_stage() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 2)]\nfn c() {}\n#[init(stage = 0)]\nfn b() {}\n#[init(stage = 0)]\nfn a() {}\n#[init(stage = 5)]\nfn d() -> u8 { 0 }\n")]);
+    let code = fixture.render(&InitOptions { stage_map: true, ..InitOptions::default() });
+    assert_in_order(&code, &[
+        "pub fn generated_init_map() -> std::collections::BTreeMap<i64, Vec<fn()>> {",
+        "\tmap.entry(0).or_default().push(crate::a);\n\tmap.entry(0).or_default().push(crate::b);\n\tmap.entry(2).or_default().push(crate::c);\n\tmap.entry(5).or_default().push(|| { let _ = crate::d(); });\n\tmap\n}",
+    ]);
+}
+
+#[test]
+fn guard_wraps_call_in_if() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0, guard = \"crate::guards::enabled\")]\nfn a() {}\n#[init(stage = 1)]\nfn b() {}\n")]);
+    let code = fixture.render(&InitOptions::default());
+    assert_in_order(&code, &["\tif crate::guards::enabled() { crate::a(); }\n", "\tcrate::b();"]);
+    let options = InitOptions { root_prefix: "super".to_string(), ..InitOptions::default() };
+    let code = fixture.render(&options);
+    assert_in_order(&code, &["\tif super::guards::enabled() { super::a(); }\n", "\tsuper::b();"]);
+}
+
+#[test]
+fn cleanup_slice_is_reversed() {
+    let fixture = Fixture::new(&[("src/lib.rs", r#"
+        #[init(stage = 0, cleanup = "crate::close_a")]
+        fn a() {}
+        #[init(stage = 1)]
+        fn b() {}
+        #[init(stage = 2, cleanup = "crate::close_c")]
+        fn c() {}
+        #[init(stage = 3, cleanup = "crate::close_d")]
+        fn d() {}
+    "#)]);
+    let code = fixture.render(&InitOptions::default());
+    assert!(code.contains("pub static GENERATED_CLEANUP: &[fn()] = &[\n\tcrate::close_d,\n\tcrate::close_c,\n\tcrate::close_a,\n];"), "{}", code);
+}
+
+#[test]
+fn multi_line_attributes_are_parsed() {
+    let fixture = Fixture::new(&[("src/lib.rs", r#"
+        #[init(
+            stage = 1,
+            description = "Opens the
+                database",
+            after = "crate::b",
+        )]
+        fn a() {}
+        #[init(
+            stage
+                =
+            1
+        )]
+        fn b() {}
+    "#)]);
+    let context = collect(&fixture.src(), &InitOptions::default(), None).unwrap();
+    let calls: Vec<&str> = context.functions.iter().map(|f| f.call.as_str()).collect();
+    assert_eq!(calls, ["crate::b", "crate::a"]);
+    assert_eq!(context.functions[1].stage, 1);
+    assert_eq!(context.functions[1].description.as_deref(), Some("Opens the\n                database"));
+}
+
+#[test]
+fn strict_module_tree_reports_orphan_files() {
+    let fixture = Fixture::new(&[
+        ("src/lib.rs", "mod db;\n"),
+        ("src/db.rs", "#[init(stage = 0)]\nfn init() {}\n"),
+        ("src/orphan.rs", "#[init(stage = 0)]\nfn init() {}\n"),
+    ]);
+    let options = InitOptions { follow_module_tree: true, strict_module_tree: true, ..InitOptions::default() };
+    match fixture.error(&options) {
+        InitError::Unreachable(unreachable) => assert_eq!(unreachable, [("crate::orphan::init".to_string(), PathBuf::from(fixture.path("src/orphan.rs")))]),
+        error => panic!("unexpected error {:?}", error),
+    }
+    fixture.write("src/orphan.rs", "fn helper() {}\n");
+    assert_eq!(fixture.calls(&options), ["crate::db::init"]);
+}
+
+#[test]
+fn skip_root_keeps_submodules() {
+    let fixture = Fixture::new(&[
+        ("src/lib.rs", "mod db;\n#[init(stage = 0)]\nfn root() {}\n"),
+        ("src/db.rs", "#[init(stage = 1)]\nfn connect() {}\n"),
+    ]);
+    for follow_module_tree in [false, true] {
+        let options = InitOptions { skip_root: true, follow_module_tree, ..InitOptions::default() };
+        assert_eq!(fixture.calls(&options), ["crate::db::connect"]);
+    }
+    assert_eq!(fixture.calls(&InitOptions::default()), ["crate::root", "crate::db::connect"]);
+}
+
+#[test]
+fn ffi_wrapper_calls_generated_init() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn start() {}\n")]);
+    let options = InitOptions { ffi_wrapper: Some("rust_init".to_string()), ..InitOptions::default() };
+    let code = fixture.render(&options);
+    assert!(code.contains("#[unsafe(no_mangle)]\npub extern \"C\" fn rust_init() {\n\tgenerated_init();\n}"), "{}", code);
+    let program = format!("fn start() {{ println!(\"started\"); }}\n{}\nfn main() {{ rust_init(); }}\n", code);
+    for edition in ["2018", "2021", "2024"] {
+        assert_eq!(run_program(&program, edition).0, "started\n");
+    }
+    let options = InitOptions { ffi_abi: "system".to_string(), fallible: true, ..options };
+    let code = fixture.render(&options);
+    assert!(code.contains("pub extern \"system\" fn rust_init() -> bool {\n\tgenerated_init().is_ok()\n}"), "{}", code);
+}
+
+#[test]
+fn stage_strategy_mixes_sequential_and_threaded() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 0)]\nfn b() {}\n#[init(stage = 1)]\nfn c() {}\n#[init(stage = 1)]\nfn d() {}\n")]);
+    let options = InitOptions { stage_strategy: [(1, Strategy::Threaded)].into_iter().collect(), ..InitOptions::default() };
+    let code = fixture.render(&options);
+    assert!(code.contains(
+        "pub fn generated_init() {\n\tcrate::a();\n\tcrate::b();\n\tstd::thread::scope(|scope| {\n\t\tscope.spawn(|| { crate::c(); });\n\t\tscope.spawn(|| { crate::d(); });\n\t});\n}"
+    ), "{}", code);
+}
+
+#[test]
+fn extra_functions_are_ordered_with_scanned_functions() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 2)]\nfn c() {}\n")]);
+    let options = InitOptions { extra_functions: vec![("crate::generated::b".to_string(), 1)], ..InitOptions::default() };
+    assert_eq!(fixture.calls(&options), ["crate::a", "crate::generated::b", "crate::c"]);
+    assert_in_order(&fixture.render(&options), &["\tcrate::a();", "\tcrate::generated::b();", "\tcrate::c();"]);
+}
+
+#[test]
+fn invalid_extra_function_path_is_rejected() {
+    let fixture = Fixture::new(&[("src/lib.rs", "")]);
+    let options = InitOptions { extra_functions: vec![("crate::init()".to_string(), 0)], ..InitOptions::default() };
+    match fixture.error(&options) {
+        InitError::InvalidOptions(message) => assert!(message.contains("crate::init()"), "{}", message),
+        error => panic!("unexpected error {:?}", error),
+    }
+}
+
+#[test]
+fn leading_colon_paths() {
+    let fixture = Fixture::new(&[("src/lib.rs", "mod db;\n#[init(stage = 0, cleanup = \"crate::db::close\")]\nfn a() {}\n"), ("src/db.rs", "#[init(stage = 1)]\nfn init() {}\n")]);
+    let code = fixture.render(&InitOptions { leading_colon: true, ..InitOptions::default() });
+    assert_in_order(&code, &["\t::a();", "\t::db::init();", "\t::db::close,"]);
+    assert!(!code.contains("crate::"), "{}", code);
+    let options = InitOptions { leading_colon: true, root_prefix: "super".to_string(), ..InitOptions::default() };
+    assert!(matches!(fixture.error(&options), InitError::InvalidOptions(_)));
+}
+
+#[test]
+fn init_check_warns_without_call() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn start() {}\n")]);
+    let options = InitOptions { init_check: true, function_name: "run_init".to_string(), ..InitOptions::default() };
+    let code = fixture.render(&options);
+    assert_in_order(&code, &[
+        "pub fn run_init() {\n\t#[cfg(debug_assertions)]\n\tgenerated_init_check::CALLED.store(true",
+        "#[cfg(debug_assertions)]\nmod generated_init_check {",
+        "unsafe extern \"C\" {",
+        "atexit(check);",
+        "unsafe(link_section = \".init_array\")",
+        "static REGISTER: extern \"C\" fn() = register;",
+    ]);
+    let warning = "warning: the program exited without calling run_init\n";
+    for edition in ["2021", "2024"] {
+        let program = format!("fn start() {{}}\n{}\nfn main() {{ if std::env::args().count() > 1 {{ run_init(); }} }}\n", code);
+        assert_eq!(run_program(&program, edition).1, warning);
+    }
+    let program = format!("fn start() {{}}\n{}\nfn main() {{ run_init(); }}\n", code);
+    assert_eq!(run_program(&program, "2024").1, "");
+}
+
+#[test]
+fn module_prefix_uses_path_components() {
+    assert_eq!(module_prefix("src", Path::new("src/lib.rs")).unwrap(), "crate");
+    assert_eq!(module_prefix("src", Path::new("src/a/b.rs")).unwrap(), "crate::a::b");
+    assert_eq!(module_prefix("src", Path::new("src/./a/mod.rs")).unwrap(), "crate::a");
+    assert_eq!(module_prefix("src/", Path::new("src/a/main.rs")).unwrap(), "crate::a::main");
+    #[cfg(windows)]
+    {
+        assert_eq!(module_prefix("src", Path::new(r"src\a\b.rs")).unwrap(), "crate::a::b");
+        assert_eq!(module_prefix(r"C:\crate\src", Path::new(r"C:\crate\src\a/mod.rs")).unwrap(), "crate::a");
+    }
+}
+
+#[test]
+fn call_overrides_emitted_path() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 1, call = \"crate::wrappers::wrap_init\")]\nfn a() {}\n#[init(stage = 0)]\nfn b() {}\n")]);
+    let code = fixture.render(&InitOptions::default());
+    assert_in_order(&code, &["\tcrate::b();", "\tcrate::wrappers::wrap_init();"]);
+    assert!(!code.contains("crate::a"), "{}", code);
+}
+
+#[test]
+fn report_lists_functions_in_call_order() {
+    let fixture = Fixture::new(&[("src/lib.rs", "mod db;\n#[init(stage = 1)]\nfn serve() {}\n"), ("src/db.rs", "\n#[init(stage = 0, description = \"connect to DB\")]\nfn init() {}\n")]);
+    let context = collect(&fixture.src(), &InitOptions::default(), None).unwrap();
+    let expected = format!(
+        "Init functions in call order, generated by rs-init {}.\n\n1. crate::db::init\n   stage: 0\n   module: crate::db\n   location: {}:3\n   description: connect to DB\n2. crate::serve\n   stage: 1\n   module: crate\n   location: {}:3\n",
+        env!("CARGO_PKG_VERSION"), fixture.path("src/db.rs"), fixture.path("src/lib.rs"),
+    );
+    assert_eq!(render_report(&context), expected);
+}
+
+#[test]
+fn phase_map_resolves_enum_stages() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = Phase::Routes)]\nfn c() {}\n#[init(stage = Phase::Db)]\nfn b() {}\n#[init(stage = Phase::Config)]\nfn a() {}\n")]);
+    let phase_map = [("Phase::Config", 0), ("Phase::Db", 1), ("Phase::Routes", 2)].into_iter().map(|(path, stage)| (path.to_string(), stage)).collect();
+    let options = InitOptions { phase_map, ..InitOptions::default() };
+    assert_eq!(fixture.calls(&options), ["crate::a", "crate::b", "crate::c"]);
+    fixture.write("src/lib.rs", "#[init(stage = Phase::Cache)]\nfn a() {}\n");
+    match fixture.error(&options) {
+        InitError::InvalidAttribute { message, .. } => assert!(message.contains("Phase::Cache"), "{}", message),
+        error => panic!("unexpected error {:?}", error),
+    }
+}
+
+#[test]
+fn run_once_runs_init_once_across_threads() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n")]);
+    let generated = fixture.render(&InitOptions { run_once: true, ..InitOptions::default() });
+    let program = generated + r#"
+        static CALLS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        fn a() {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            CALLS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+        fn main() {
+            let threads: Vec<_> = (0..8).map(|_| std::thread::spawn(|| {
+                generated_init();
+                assert_eq!(CALLS.load(std::sync::atomic::Ordering::SeqCst), 1);
+            })).collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            generated_init();
+            println!("{}", CALLS.load(std::sync::atomic::Ordering::SeqCst));
+        }
+    "#;
+    assert_eq!(run_program(&program, "2021").0, "1\n");
+
+    fixture.write("src/lib.rs", "#[init(stage = 0)]\nfn a() -> Result<(), Box<dyn std::error::Error + Send + Sync>> { Ok(()) }\n");
+    let options = InitOptions { run_once: true, fallible: true, error_type: "Box<dyn std::error::Error + Send + Sync>".to_string(), ..InitOptions::default() };
+    let program = fixture.render(&options) + r#"
+        static CALLS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        fn a() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            CALLS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err("failed".into())
+        }
+        fn main() {
+            let threads: Vec<_> = (0..8).map(|_| std::thread::spawn(|| generated_init().unwrap_err().to_string())).collect();
+            for thread in threads {
+                assert_eq!(thread.join().unwrap(), "failed");
+            }
+            println!("{}", CALLS.load(std::sync::atomic::Ordering::SeqCst));
+        }
+    "#;
+    assert_eq!(run_program(&program, "2021").0, "1\n");
+}
+
+#[test]
+fn exclude_fn_patterns_drop_matching_functions() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn test_db() {}\n#[init(stage = 0)]\nfn db() {}\n#[init(stage = 1)]\nfn mock_server() {}\n#[init(stage = 1)]\nfn server() {}\n")]);
+    let options = InitOptions { exclude_fn_patterns: vec!["test_*".to_string(), "mock_*".to_string()], ..InitOptions::default() };
+    assert_eq!(fixture.calls(&options), ["crate::db", "crate::server"]);
+    let options = InitOptions { exclude_fn_patterns: vec!["[".to_string()], ..InitOptions::default() };
+    assert!(matches!(fixture.error(&options), InitError::InvalidOptions(_)));
+    // The patterns are checked before the scan, even if no init function is found.
+    let fixture = Fixture::new(&[("src/lib.rs", "fn db() {}\n")]);
+    assert!(matches!(fixture.error(&options), InitError::InvalidOptions(_)));
+}
+
+#[test]
+fn stage_enum_lists_discovered_stages() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 3)]\nfn c() {}\n#[init(stage = -1)]\nfn a() {}\n#[init(stage = 3)]\nfn d() {}\n#[init(stage = 0)]\nfn b() {}\n")]);
+    let code = fixture.render(&InitOptions { stage_enum: true, ..InitOptions::default() });
+    assert!(code.contains("pub enum GeneratedStage {\n\tStageM1,\n\tStage0,\n\tStage3,\n}"), "{}", code);
+    assert!(code.contains("\t\t\t-1 => Some(Self::StageM1),\n\t\t\t0 => Some(Self::Stage0),\n\t\t\t3 => Some(Self::Stage3),\n\t\t\t_ => None,"), "{}", code);
+    assert!(code.contains("\t\t\tSelf::StageM1 => -1,\n\t\t\tSelf::Stage0 => 0,\n\t\t\tSelf::Stage3 => 3,\n\t\t}"), "{}", code);
+}
+
+#[test]
+fn require_init_per_module_names_empty_modules() {
+    let fixture = Fixture::new(&[
+        ("src/lib.rs", "mod db;\nmod util;\n"),
+        ("src/db.rs", "#[init(stage = 0)]\nfn init() {}\n"),
+        ("src/util.rs", "fn helper() {}\n"),
+    ]);
+    for follow_module_tree in [false, true] {
+        let options = InitOptions { require_init_per_module: true, follow_module_tree, ..InitOptions::default() };
+        match fixture.error(&options) {
+            InitError::EmptyModules(modules) => assert_eq!(modules, [("crate::util".to_string(), PathBuf::from(fixture.path("src/util.rs")))]),
+            error => panic!("unexpected error {:?}", error),
+        }
+    }
+    assert_eq!(fixture.calls(&InitOptions::default()), ["crate::db::init"]);
+}
+
+#[test]
+fn pins_bracket_stage_order() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 1)]\nfn b() {}\n#[init(stage = 2)]\nfn c() {}\n#[init(stage = 3)]\nfn d() {}\n#[init(stage = 4)]\nfn e() {}\n")]);
+    let paths = |paths: &[&str]| paths.iter().map(|path| path.to_string()).collect::<Vec<_>>();
+    let options = InitOptions { pin_first: paths(&["crate::d", "crate::c"]), pin_last: paths(&["crate::a"]), ..InitOptions::default() };
+    assert_eq!(fixture.calls(&options), ["crate::d", "crate::c", "crate::b", "crate::e", "crate::a"]);
+    let options = InitOptions { pin_last: paths(&["crate::missing"]), ..InitOptions::default() };
+    match fixture.error(&options) {
+        InitError::InvalidOptions(message) => assert!(message.contains("crate::missing"), "{}", message),
+        error => panic!("unexpected error {:?}", error),
+    }
+}
+
+#[test]
+fn double_init_attribute_is_rejected() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\n#[init(stage = 2)]\nfn a() {}\n")]);
+    match fixture.error(&InitOptions::default()) {
+        InitError::InvalidAttribute { function, message, .. } => {
+            assert_eq!(function, "a");
+            assert!(message.contains("more than one #[init] attribute"), "{}", message);
+        }
+        error => panic!("unexpected error {:?}", error),
+    }
+}
+
+#[test]
+fn stage_counts_match_functions() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 0)]\nfn b() {}\n#[init(stage = 0)]\nfn c() {}\n#[init(stage = 2)]\nfn d() {}\n#[init(stage = -1)]\nfn e() {}\n")]);
+    let code = fixture.render(&InitOptions { stage_counts: true, ..InitOptions::default() });
+    assert_in_order(&code, &["pub const INIT_STAGE_M1_COUNT: usize = 1;\n", "pub const INIT_STAGE_0_COUNT: usize = 3;\n", "pub const INIT_STAGE_2_COUNT: usize = 1;\n"]);
+}
+
+#[test]
+fn extensions_select_scanned_files() {
+    let fixture = Fixture::new(&[
+        ("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n"),
+        ("src/plugins.rs.in", "#[init(stage = 1)]\nfn init() {}\n"),
+        ("src/notes.txt", "#[init(stage = 2)]\nfn init() {}\n"),
+    ]);
+    assert_eq!(fixture.calls(&InitOptions::default()), ["crate::a"]);
+    let options = InitOptions { extensions: vec![".rs".to_string(), ".rs.in".to_string()], ..InitOptions::default() };
+    assert_eq!(fixture.calls(&options), ["crate::a", "crate::plugins::init"]);
+}
+
+#[test]
+fn small_async_stages_use_join_macro() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nasync fn a() {}\n#[init(stage = 0)]\nasync fn b() {}\n#[init(stage = 1)]\nasync fn c() {}\n#[init(stage = 1)]\nasync fn d() {}\n#[init(stage = 1)]\nasync fn e() {}\n")]);
+    let options = InitOptions { asynchronous: true, join_stages: true, join_macro_max: Some(2), ..InitOptions::default() };
+    let code = fixture.render(&options);
+    assert_in_order(&code, &["\t::tokio::join!(crate::a(), crate::b());\n", "\t\t::futures::future::join_all(stage).await;"]);
+}
+
+#[test]
+fn init_ids_are_stable() {
+    let files = [
+        ("src/lib.rs", "mod net;\n#[init(stage = 1)]\nfn b() {}\n#[init(stage = 0)]\nfn a() {}\n"),
+        ("src/net.rs", "#[init(stage = 1)]\nfn init() {}\n"),
+    ];
+    let ids = |files: &[(&str, &str)]| {
+        let code = Fixture::new(files).render(&InitOptions { init_ids: true, ..InitOptions::default() });
+        code[code.find("pub const INIT_IDS").unwrap()..].to_string()
+    };
+    let first = ids(&files);
+    assert_eq!(first, "pub const INIT_IDS: &[(&str, u32)] = &[\n\t(\"crate::a\", 0),\n\t(\"crate::b\", 1),\n\t(\"crate::net::init\", 2),\n];\n");
+    let mut reversed = files;
+    reversed.reverse();
+    assert_eq!(ids(&reversed), first);
+}
+
+#[test]
+fn qualified_attribute_path_is_found() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[rs_init_macro::init(stage = 1)]\nfn a() {}\n#[::rs_init_macro::init(stage = 0)]\nfn b() {}\n#[other::noinit(stage = 0)]\nfn c() {}\n")]);
+    assert_eq!(fixture.calls(&InitOptions::default()), ["crate::b", "crate::a"]);
+}
+
+#[test]
+fn stage_offsets_move_directories_later() {
+    let fixture = Fixture::new(&[
+        ("src/lib.rs", "mod plugins;\n#[init(stage = 5)]\nfn core() {}\n"),
+        ("src/plugins/mod.rs", "mod audio;\n"),
+        ("src/plugins/audio.rs", "#[init(stage = 0)]\nfn init() {}\n"),
+    ]);
+    assert_eq!(fixture.calls(&InitOptions::default()), ["crate::plugins::audio::init", "crate::core"]);
+    let options = InitOptions { stage_offsets: vec![(fixture.path("src/plugins"), 100)], ..InitOptions::default() };
+    assert_eq!(fixture.calls(&options), ["crate::core", "crate::plugins::audio::init"]);
+    assert!(fixture.render(&options).contains("\tcrate::core();\n\tcrate::plugins::audio::init();"));
+}
+
+#[test]
+fn step_stream_yields_before_each_call() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 1)]\nasync fn b() {}\n#[init(stage = 0)]\nfn a() {}\n")]);
+    let code = fixture.render(&InitOptions { step_stream: true, asynchronous: true, ..InitOptions::default() });
+    assert!(code.contains(
+        "pub fn generated_init_steps() -> impl ::futures::Stream<Item = &'static str> {\n\t::async_stream::stream! {\n\t\tyield \"crate::a\";\n\t\tcrate::a();\n\t\tyield \"crate::b\";\n\t\tcrate::b().await;\n\t}\n}"
+    ), "{}", code);
+}
+
+#[test]
+fn rerun_per_file_tracks_every_source_file() {
+    let fixture = Fixture::new(&[("src/lib.rs", "mod a;\n"), ("src/a.rs", "#[init(stage = 0)]\nfn init() {}\n"), ("src/b/mod.rs", "")]);
+    assert!(collect(&fixture.src(), &InitOptions::default(), None).unwrap().tracked_files.is_empty());
+    let options = InitOptions { rerun_per_file: true, ..InitOptions::default() };
+    let mut tracked = collect(&fixture.src(), &options, None).unwrap().tracked_files;
+    tracked.sort();
+    assert_eq!(tracked, [fixture.path("src/a.rs"), fixture.path("src/b/mod.rs"), fixture.path("src/lib.rs")]);
+}
+
+#[test]
+fn binaries_get_separate_files() {
+    let fixture = Fixture::new(&[
+        ("src/main.rs", "mod shared;\n#[init(stage = 0)]\nfn main_init() {}\n"),
+        ("src/shared.rs", "#[init(stage = 1)]\nfn init() {}\n"),
+        ("src/bin/server.rs", "mod net;\n#[init(stage = 0)]\nfn server_init() {}\n"),
+        ("src/bin/net.rs", "#[init(stage = 1)]\nfn init() {}\n"),
+        ("src/bin/tool/main.rs", "#[init(stage = 0)]\nfn tool_init() {}\n"),
+    ]);
+    let out_dir = fixture.path("out");
+    std::fs::create_dir(&out_dir).unwrap();
+    generate_init_function_per_binary_with(&fixture.src(), &InitOptions { out_dir: Some(out_dir.into()), ..InitOptions::default() });
+    let read = |file: &str| std::fs::read_to_string(fixture.path(&format!("out/{}", file))).unwrap();
+    assert!(read("init_main.rs").contains("pub fn generated_init() {\n\tcrate::main_init();\n\tcrate::shared::init();\n}"));
+    assert!(read("init_server.rs").contains("pub fn generated_init() {\n\tcrate::server_init();\n\tcrate::net::init();\n}"));
+    assert!(read("init_tool.rs").contains("pub fn generated_init() {\n\tcrate::tool_init();\n}"));
+}
+
+#[test]
+fn timed_records_every_function() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 1)]\nfn b() {}\n#[init(stage = 0)]\nfn a() {}\n")]);
+    let generated = fixture.render(&InitOptions { timed: true, ..InitOptions::default() });
+    assert_in_order(&generated, &[
+        "pub fn generated_init_timed() -> Vec<(&'static str, std::time::Duration)> {",
+        "\t\tlet start = std::time::Instant::now();\n\t\tcrate::a();\n\t\ttimings.push((\"crate::a\", start.elapsed()));",
+        "\t\tlet start = std::time::Instant::now();\n\t\tcrate::b();\n\t\ttimings.push((\"crate::b\", start.elapsed()));",
+        "\ttimings\n}",
+    ]);
+    let program = generated + r#"
+        fn a() {}
+        fn b() {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        fn main() {
+            for (path, duration) in generated_init_timed() {
+                println!("{} {}", path, duration >= std::time::Duration::from_millis(5));
+            }
+        }
+    "#;
+    assert_eq!(run_program(&program, "2021").0, "crate::a false\ncrate::b true\n");
+}
+
+#[test]
+fn conditional_stage_gates_every_branch() {
+    let fixture = Fixture::new(&[("src/lib.rs", r#"
+        #[init(stage = if cfg!(feature = "fast") { 0 } else { 5 })]
+        fn cache() {}
+        #[init(stage = 2)]
+        fn db() {}
+    "#)]);
+    let code = fixture.render(&InitOptions::default());
+    assert!(code.contains(
+        "pub fn generated_init() {\n\t#[cfg(feature = \"fast\")]\n\tcrate::cache();\n\tcrate::db();\n\t#[cfg(not(feature = \"fast\"))]\n\tcrate::cache();\n}"
+    ), "{}", code);
+}
+
+#[test]
+fn trampolines_wrap_calls() {
+    let fixture = Fixture::new(&[("src/lib.rs", "mod db;\n#[init(stage = 1)]\nfn serve() -> u8 { 0 }\n"), ("src/db.rs", "#[init(stage = 0)]\nfn connect() {}\n")]);
+    let code = fixture.render(&InitOptions { trampolines: true, ..InitOptions::default() });
+    assert_in_order(&code, &[
+        "\tgenerated_init_trampolines::crate__db__connect(crate::db::connect);",
+        "\tlet _ = generated_init_trampolines::crate__serve(crate::serve);",
+        "mod generated_init_trampolines {",
+        "\t#[inline(never)]\n\tpub fn crate__db__connect<T>(f: impl FnOnce() -> T) -> T {\n\t\tf()\n\t}",
+        "\t#[inline(never)]\n\tpub fn crate__serve<T>(f: impl FnOnce() -> T) -> T {",
+    ]);
+}
+
+#[test]
+fn single_file_scans_inline_modules() {
+    let fixture = Fixture::new(&[
+        ("src/lib.rs", r#"
+            #[init(stage = 2)]
+            fn root() {}
+            mod db {
+                #[init(stage = 0)]
+                fn connect() {}
+                pub mod pool {
+                    #[init(stage = 1)]
+                    fn init() {}
+                }
+            }
+            mod other;
+        "#),
+        ("src/other.rs", "#[init(stage = 0)]\nfn init() {}\n"),
+    ]);
+    let context = collect_file(&fixture.path("src/lib.rs"), "crate::bundle", &InitOptions::default()).unwrap();
+    let calls: Vec<String> = context.functions.into_iter().map(|f| f.call).collect();
+    assert_eq!(calls, ["crate::bundle::db::connect", "crate::bundle::db::pool::init", "crate::bundle::root"]);
+    assert!(matches!(collect_file(&fixture.path("src/lib.rs"), "crate::", &InitOptions::default()), Err(InitError::InvalidOptions(_))));
+}
+
+#[test]
+fn continue_on_panic_runs_remaining_functions() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 1)]\nfn b() {}\n#[init(stage = 2)]\nfn c() {}\n")]);
+    let options = InitOptions { continue_on_panic: true, failure_hook: Some("crate::report".to_string()), ..InitOptions::default() };
+    let generated = fixture.render(&options);
+    assert_in_order(&generated, &[
+        "pub fn generated_init_resilient() -> Vec<String> {",
+        "\t\tlet result = std::panic::catch_unwind(|| {\n\t\t\tcrate::a();\n\t\t});",
+        "\t\tlet result = std::panic::catch_unwind(|| {\n\t\t\tcrate::b();\n\t\t});\n\t\tif !matches!(result, Ok(())) {\n\t\t\tcrate::report(\"crate::b\");\n\t\t\tfailed.push(\"crate::b\".to_string());",
+    ]);
+    let program = generated + r#"
+        fn a() { println!("a"); }
+        fn b() { panic!("b failed"); }
+        fn c() { println!("c"); }
+        fn report(path: &str) { println!("report {}", path); }
+        fn main() {
+            std::panic::set_hook(Box::new(|_| {}));
+            println!("{:?}", generated_init_resilient());
+        }
+    "#;
+    assert_eq!(run_program(&program, "2021").0, "a\nreport crate::b\nc\n[\"crate::b\"]\n");
+}
+
+#[test]
+fn descriptions_are_in_metadata() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0, description = \"connect to \\\"DB\\\"\")]\nfn a() {}\n#[init(stage = 1)]\nfn b() {}\n")]);
+    let code = fixture.render(&InitOptions { metadata: true, ..InitOptions::default() });
+    assert!(code.contains(
+        "pub const GENERATED_INIT_METADATA: &[(&str, i64, &str)] = &[\n\t(\"crate::a\", 0, \"connect to \\\"DB\\\"\"),\n\t(\"crate::b\", 1, \"crate::b\"),\n];"
+    ), "{}", code);
+}
+
+#[test]
+fn hash_tie_break_is_stable() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 0)]\nfn b() {}\n#[init(stage = 0)]\nfn c() {}\n#[init(stage = 0)]\nfn d() {}\n#[init(stage = 0)]\nfn e() {}\n#[init(stage = 1)]\nfn f() {}\n")]);
+    let options = InitOptions { tie_break: TieBreak::Hash, ..InitOptions::default() };
+    assert_eq!(fixture.calls(&options), ["crate::d", "crate::e", "crate::a", "crate::b", "crate::c", "crate::f"]);
+    // FNV-1a of "a", the hash does not depend on the Rust version or the process.
+    assert_eq!(path_hash("a"), 0xaf63dc4c8601ec8c);
+}
+
+#[test]
+fn async_aggregate_collects_all_errors() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nasync fn a() -> Result<(), E> { Ok(()) }\n#[init(stage = 0)]\nasync fn b() -> Result<(), E> { Ok(()) }\n#[init(stage = 1)]\nasync fn c() -> Result<(), E> { Ok(()) }\n")]);
+    let options = InitOptions {
+        asynchronous: true,
+        fallible: true,
+        error_type: "E".to_string(),
+        error_aggregator: Some("crate::Errors::new".to_string()),
+        stage_strategy: [(0, Strategy::AsyncAggregate)].into_iter().collect(),
+        ..InitOptions::default()
+    };
+    let code = fixture.render(&options);
+    assert!(code.contains(concat!(
+        "\t{\n",
+        "\t\tlet mut stage: Vec<std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), E>>>>> = Vec::new();\n",
+        "\t\tstage.push(Box::pin(async { crate::a().await?; Ok::<(), E>(()) }));\n",
+        "\t\tstage.push(Box::pin(async { crate::b().await?; Ok::<(), E>(()) }));\n",
+        "\t\tlet errors: Vec<E> = ::futures::future::join_all(stage).await.into_iter().filter_map(Result::err).collect();\n",
+        "\t\tif !errors.is_empty() {\n",
+        "\t\t\treturn Err(crate::Errors::new(errors));\n",
+        "\t\t}\n",
+        "\t}\n",
+        "\tcrate::c().await?;\n",
+    )), "{}", code);
+    let options = InitOptions { error_aggregator: None, ..options };
+    assert!(matches!(fixture.error(&options), InitError::InvalidOptions(_)));
+}
+
+#[test]
+fn init_table_is_sized_array() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 1)]\nfn c() {}\n#[init(stage = 0)]\nfn a() {}\n#[init(stage = 0)]\nfn b() -> u8 { 0 }\n")]);
+    let generated = fixture.render(&InitOptions { init_table: true, ..InitOptions::default() });
+    assert!(generated.contains("pub static INIT_TABLE: [fn(); 3] = [\n\tcrate::a,\n\t|| { let _ = crate::b(); },\n\tcrate::c,\n];"), "{}", generated);
+    let program = generated + r#"
+        fn a() { print!("a"); }
+        fn b() -> u8 { print!("b"); 0 }
+        fn c() { println!("c"); }
+        fn main() {
+            INIT_TABLE.iter().for_each(|init| init());
+        }
+    "#;
+    assert_eq!(run_program(&program, "2021").0, "abc\n");
+}
+
+#[test]
+fn explicit_out_dir_does_not_need_out_dir_variable() {
+    let _env = env_lock();
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n")]);
+    let out_dir = fixture.path("generated");
+    std::fs::create_dir(&out_dir).unwrap();
+    let previous = std::env::var_os("OUT_DIR");
+    std::env::remove_var("OUT_DIR");
+    let missing = try_generate_init_function_with(&fixture.src(), &InitOptions::default());
+    let written = try_generate_init_function_with(&fixture.src(), &InitOptions { out_dir: Some(out_dir.into()), ..InitOptions::default() });
+    if let Some(previous) = previous {
+        std::env::set_var("OUT_DIR", previous);
+    }
+    assert!(matches!(missing, Err(InitError::MissingOutDir)));
+    written.unwrap();
+    let code = std::fs::read_to_string(fixture.path("generated/init.rs")).unwrap();
+    assert!(code.contains("pub fn generated_init() {\n\tcrate::a();\n}"), "{}", code);
+}
+
+#[test]
+fn register_macro_replaces_calls() {
+    let fixture = Fixture::new(&[("src/lib.rs", "mod db;\n#[init(stage = 2)]\nfn serve() {}\n"), ("src/db.rs", "#[init(stage = 0)]\nfn init() {}\n")]);
+    let code = fixture.render(&InitOptions { register_macro: Some("my_framework::register".to_string()), ..InitOptions::default() });
+    assert!(code.contains("pub fn generated_init() {\n\tmy_framework::register!(crate::db::init, 0);\n\tmy_framework::register!(crate::serve, 2);\n}"), "{}", code);
+}
+
+#[test]
+fn max_reasonable_stage_warns_about_large_stages() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 100)]\nfn a() {}\n#[init(stage = 1000000)]\nfn b() {}\n")]);
+    let options = InitOptions { max_reasonable_stage: Some(100), ..InitOptions::default() };
+    let warnings = collect(&fixture.src(), &options, None).unwrap().warnings;
+    assert_eq!(warnings, [format!("{}:4: stage 1000000 of crate::b is above 100, it may be a typo", fixture.path("src/lib.rs"))]);
+    assert!(collect(&fixture.src(), &InitOptions::default(), None).unwrap().warnings.is_empty());
+}
+
+#[test]
+fn active_cfgs_prune_disabled_functions() {
+    let fixture = Fixture::new(&[("src/lib.rs", r#"
+        #[init(stage = 0)]
+        #[cfg(feature = "fast")]
+        fn fast() {}
+        #[init(stage = 0)]
+        #[cfg(not(feature = "fast"))]
+        fn slow() {}
+        #[init(stage = 1)]
+        #[cfg(all(unix, not(feature = "fast")))]
+        fn unix() {}
+        #[init(stage = 2)]
+        #[cfg(accessible(::std::thread))]
+        fn unknown() {}
+    "#)]);
+    let active = |cfgs: &[&str]| InitOptions { active_cfgs: Some(cfgs.iter().map(|cfg| cfg.to_string()).collect()), ..InitOptions::default() };
+    let code = fixture.render(&active(&["feature=\"fast\"", "unix"]));
+    assert!(code.contains("pub fn generated_init() {\n\tcrate::fast();\n\t#[cfg(accessible (:: std :: thread))]\n\tcrate::unknown();\n}"), "{}", code);
+    let code = fixture.render(&active(&["unix"]));
+    assert!(code.contains("pub fn generated_init() {\n\tcrate::slow();\n\tcrate::unix();\n\t#[cfg(accessible (:: std :: thread))]\n\tcrate::unknown();\n}"), "{}", code);
+}
+
+#[test]
+fn path_transform_rewrites_every_path() {
+    let fixture = Fixture::new(&[("src/lib.rs", "mod db;\n#[init(stage = 1, after = \"crate::db::init\")]\nfn serve() {}\n"), ("src/db.rs", "#[init(stage = 1)]\nfn init() {}\n")]);
+    let transform = PathTransform::new(|path| path.replace("::db::", "::DB::"));
+    let options = InitOptions { path_transform: Some(transform), ..InitOptions::default() };
+    let context = collect(&fixture.src(), &options, None).unwrap();
+    let calls: Vec<&str> = context.functions.iter().map(|f| f.call.as_str()).collect();
+    assert_eq!(calls, ["crate::DB::init", "crate::serve"]);
+    assert_eq!(context.functions[1].after, ["crate::DB::init"]);
+}
+
+#[test]
+fn verify_test_checks_metadata_order() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 1)]\nfn b() {}\n#[init(stage = 0)]\nfn a() {}\n")]);
+    let options = InitOptions { metadata: true, verify_test: true, ..InitOptions::default() };
+    let code = fixture.render(&options);
+    assert_in_order(&code, &[
+        "pub const GENERATED_INIT_METADATA",
+        "#[test]\nfn verify_generated_init_order() {\n\tlet expected: &[&str] = &[\n\t\t\"crate::a\",\n\t\t\"crate::b\",\n\t];",
+        "\tlet actual: Vec<&str> = GENERATED_INIT_METADATA.iter().map(|(path, _, _)| *path).collect();\n\tassert_eq!(actual, expected);",
+        "GENERATED_INIT_METADATA.windows(2).all(|pair| pair[0].1 <= pair[1].1)",
+    ]);
+    let options = InitOptions { metadata: false, ..options };
+    assert!(matches!(fixture.error(&options), InitError::InvalidOptions(_)));
+}
+
+#[test]
+fn sidecar_entries_are_ordered_with_scanned_functions() {
+    let fixture = Fixture::new(&[
+        ("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 2)]\nfn c() {}\n"),
+        ("init.json", r#"[{ "path": "crate::ffi::b", "stage": 1, "description": "foreign" }, { "path": "crate::ffi::d", "stage": 3 }]"#),
+    ]);
+    let options = InitOptions { sidecar: Some(fixture.path("init.json").into()), ..InitOptions::default() };
+    let context = collect(&fixture.src(), &options, None).unwrap();
+    let calls: Vec<&str> = context.functions.iter().map(|f| f.call.as_str()).collect();
+    assert_eq!(calls, ["crate::a", "crate::ffi::b", "crate::c", "crate::ffi::d"]);
+    assert_eq!(context.functions[1].description.as_deref(), Some("foreign"));
+    fixture.write("init.json", r#"[{ "path": "crate::ffi::b(", "stage": 1 }]"#);
+    assert!(matches!(fixture.error(&options), InitError::InvalidOptions(_)));
+}
+
+#[test]
+fn cold_marks_generated_init() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n")]);
+    let code = fixture.render(&InitOptions { cold: true, ..InitOptions::default() });
+    assert!(code.contains("#[cold]\n#[inline(never)]\npub fn generated_init() {"), "{}", code);
+    assert!(!fixture.render(&InitOptions::default()).contains("#[cold]"));
+}
+
+#[test]
+fn pass_name_passes_function_path() {
+    let fixture = Fixture::new(&[("src/lib.rs", "mod db;\n"), ("src/db.rs", "#[init(stage = 0)]\nfn init(name: &'static str) {}\n")]);
+    let options = InitOptions { pass_name: true, ..InitOptions::default() };
+    assert!(fixture.render(&options).contains("\tcrate::db::init(\"crate::db::init\");"));
+}
+
+#[test]
+fn pass_name_requires_static_str() {
+    for parameters in ["", "name: &str", "name: &'static mut str", "name: String", "a: &'static str, b: u8"] {
+        let fixture = Fixture::new(&[("src/lib.rs", &format!("#[init(stage = 0)]\nfn init({}) {{}}\n", parameters))]);
+        match fixture.error(&InitOptions { pass_name: true, ..InitOptions::default() }) {
+            InitError::InvalidAttribute { message, .. } => assert!(message.contains("&'static str"), "{}", message),
+            error => panic!("unexpected error {:?} for ({})", error, parameters),
+        }
+    }
+}
+
+#[test]
+fn init_plan_lists_entries() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 1, group = \"core\", description = \"connect\")]\nfn b() {}\n#[init(stage = 0)]\nfn a() {}\n")]);
+    let code = fixture.render(&InitOptions { init_plan: true, ..InitOptions::default() });
+    assert!(code.contains(concat!(
+        "pub fn init_plan() -> ::rs_init::InitPlan {\n",
+        "\tlet mut entries = Vec::new();\n",
+        "\tentries.push(::rs_init::InitPlanEntry { path: \"crate::a\", stage: 0, group: None, description: None });\n",
+        "\tentries.push(::rs_init::InitPlanEntry { path: \"crate::b\", stage: 1, group: Some(\"core\"), description: Some(\"connect\") });\n",
+        "\t::rs_init::InitPlan { entries }\n",
+        "}",
+    )), "{}", code);
+    let plan = InitPlan {
+        entries: vec![
+            InitPlanEntry { path: "crate::a", stage: 0, group: None, description: None },
+            InitPlanEntry { path: "crate::b", stage: 1, group: Some("core"), description: Some("connect") },
+        ],
+    };
+    assert_eq!(serde_json::to_value(&plan).unwrap(), serde_json::json!({
+        "entries": [
+            { "path": "crate::a", "stage": 0, "group": null, "description": null },
+            { "path": "crate::b", "stage": 1, "group": "core", "description": "connect" },
+        ],
+    }));
+}
+
+#[test]
+fn first_runs_before_lower_stages() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = -5)]\nfn early() {}\n#[init(stage = 3, first = true)]\nfn logger() {}\n#[init(stage = 0)]\nfn db() {}\n#[init(stage = 1, first = true)]\nfn config() {}\n")]);
+    assert_eq!(fixture.calls(&InitOptions::default()), ["crate::config", "crate::logger", "crate::early", "crate::db"]);
+}
+
+#[test]
+fn scan_parsed_matches_scanning_files() {
+    let files = [
+        ("src/lib.rs", "mod db;\n#[init(stage = 1)]\nfn serve() {}\nmod inline {\n\t#[init(stage = 0, description = \"inline\")]\n\tfn init() {}\n}\n"),
+        ("src/db.rs", "#[init(stage = 0)]\nfn connect() -> u8 { 0 }\n"),
+    ];
+    let fixture = Fixture::new(&files);
+    let options = InitOptions { metadata: true, ..InitOptions::default() };
+    let parsed: Vec<(String, syn::File)> = [("crate", files[0].1), ("crate::db", files[1].1)].into_iter()
+        .map(|(prefix, content)| (prefix.to_string(), syn::parse_file(content).unwrap()))
+        .collect();
+    assert_eq!(scan_parsed(&parsed, &options).unwrap(), fixture.render(&options));
+    let options = InitOptions { stage_offsets: vec![("crate::db".to_string(), 10)], ..InitOptions::default() };
+    assert!(matches!(scan_parsed(&parsed, &options), Err(InitError::InvalidOptions(_))));
+}
+
+#[test]
+fn dot_graph_has_nodes_and_edges() {
+    let fixture = Fixture::new(&[("src/lib.rs", r#"
+        #[init(stage = 0, before = "crate::c")]
+        fn a() {}
+        #[init(stage = 1, after = ["crate::a", "crate::c"])]
+        fn b() {}
+        #[init(stage = 1, after = "crate::a")]
+        fn c() {}
+    "#)]);
+    let context = collect(&fixture.src(), &InitOptions::default(), None).unwrap();
+    assert_eq!(render_dot(&context), concat!(
+        "digraph init {\n",
+        "\t\"crate::a\" [label=\"crate::a\\nstage 0\"];\n",
+        "\t\"crate::c\" [label=\"crate::c\\nstage 1\"];\n",
+        "\t\"crate::b\" [label=\"crate::b\\nstage 1\"];\n",
+        "\t\"crate::a\" -> \"crate::c\";\n",
+        "\t\"crate::a\" -> \"crate::b\";\n",
+        "\t\"crate::c\" -> \"crate::b\";\n",
+        "}\n",
+    ));
+}
+
+#[test]
+fn timeout_wraps_await() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0, timeout_ms = 5000)]\nasync fn a() -> Result<(), Error> { Ok(()) }\n#[init(stage = 1)]\nasync fn b() -> Result<(), Error> { Ok(()) }\n")]);
+    let options = InitOptions { asynchronous: true, fallible: true, ..InitOptions::default() };
+    let code = fixture.render(&options);
+    assert_in_order(&code, &["\t::tokio::time::timeout(::std::time::Duration::from_millis(5000), crate::a()).await??;\n", "\tcrate::b().await?;\n"]);
+    assert!(matches!(fixture.error(&InitOptions { asynchronous: true, ..InitOptions::default() }), InitError::Incompatible { .. }));
+}
+
+#[test]
+fn explain_lists_ordering_factors() {
+    let fixture = Fixture::new(&[("src/lib.rs", r#"
+        #[init(stage = 0, group = "core")]
+        fn config() {}
+        #[init(stage = 1, first = true)]
+        fn logger() {}
+        #[init(stage = 0, after = "crate::config")]
+        fn db() {}
+        #[init(stage = 2, group = "plugins")]
+        fn audio() {}
+    "#)]);
+    let options = InitOptions { group_order: vec!["core".to_string()], pin_last: vec!["crate::config".to_string()], ..InitOptions::default() };
+    let context = collect(&fixture.src(), &options, None).unwrap();
+    let explain = render_explain(&context, &options);
+    let body = explain.split_once('\n').unwrap().1;
+    assert_eq!(body, concat!(
+        "\ncrate::logger\n   index: 0\n   first = true\n   no group, after all groups\n   stage 1\n   ties broken by module crate and name logger\n",
+        "\ncrate::audio\n   index: 1\n   group plugins, not in the group order\n   stage 2\n   ties broken by module crate and name audio\n",
+        "\ncrate::config\n   index: 2\n   group core, position 0 in the group order\n   stage 0\n   ties broken by module crate and name config\n   pinned last, position 0\n",
+        "\ncrate::db\n   index: 3\n   no group, after all groups\n   stage 0\n   ties broken by module crate and name db\n   after crate::config\n",
+    ));
+}
+
+#[test]
+fn slot_accepts_cfg_exclusive_functions_only() {
+    let fixture = Fixture::new(&[("src/lib.rs", r#"
+        #[init(stage = 0, slot = "storage")]
+        #[cfg(feature = "s3")]
+        fn s3() {}
+        #[init(stage = 0, slot = "storage")]
+        #[cfg(not(feature = "s3"))]
+        fn disk() {}
+    "#)]);
+    let code = fixture.render(&InitOptions::default());
+    assert_in_order(&code, &["\t#[cfg(not (feature = \"s3\"))]\n\tcrate::disk();", "\t#[cfg(feature = \"s3\")]\n\tcrate::s3();"]);
+    fixture.write("src/lib.rs", "#[init(stage = 0, slot = \"storage\")]\n#[cfg(feature = \"s3\")]\nfn s3() {}\n#[init(stage = 1, slot = \"storage\")]\nfn disk() {}\n");
+    match fixture.error(&InitOptions::default()) {
+        InitError::SlotConflict { slot, calls } => {
+            assert_eq!(slot, "storage");
+            assert_eq!(calls, ["crate::s3", "crate::disk"]);
+        }
+        error => panic!("unexpected error {:?}", error),
+    }
+}
+
+#[test]
+fn auto_fallible_follows_result_returning_functions() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 1)]\nfn b() -> Result<(), String> { Ok(()) }\n")]);
+    let options = InitOptions { auto_fallible: true, error_type: "String".to_string(), ..InitOptions::default() };
+    let code = fixture.render(&options);
+    assert!(code.contains("pub fn generated_init() -> Result<(), String> {\n\tcrate::a();\n\tcrate::b()?;\n\tOk(())\n}"), "{}", code);
+    fixture.write("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 1)]\nfn b() {}\n");
+    let code = fixture.render(&options);
+    assert!(code.contains("pub fn generated_init() {\n\tcrate::a();\n\tcrate::b();\n}"), "{}", code);
+}
+
+#[test]
+fn split_async_separates_async_functions() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n#[init(stage = 1)]\nasync fn b() {}\n#[init(stage = 2)]\nfn c() {}\n")]);
+    let code = fixture.render(&InitOptions { split_async: true, ..InitOptions::default() });
+    assert_in_order(&code, &[
+        "pub fn generated_init() {\n\tcrate::a();\n\tcrate::c();\n}",
+        "pub async fn generated_init_async() {\n\tcrate::b().await;\n}",
+    ]);
+    match fixture.error(&InitOptions::default()) {
+        InitError::Incompatible { call, .. } => assert_eq!(call, "crate::b"),
+        error => panic!("unexpected error {:?}", error),
+    }
+    let options = InitOptions { split_async: true, timed: true, ..InitOptions::default() };
+    assert!(matches!(fixture.error(&options), InitError::InvalidOptions(_)));
+    let options = InitOptions { split_async: true, stage_names: vec!["boot".to_string()], ..InitOptions::default() };
+    assert!(matches!(fixture.error(&options), InitError::InvalidOptions(_)));
+}
+
+#[test]
+fn dependencies_split_concurrent_async_stage() {
+    let fixture = Fixture::new(&[("src/lib.rs", r#"
+        #[init(stage = 1, after = "crate::b")]
+        async fn a() {}
+        #[init(stage = 1)]
+        async fn b() {}
+        #[init(stage = 1)]
+        async fn c() {}
+    "#)]);
+    let code = fixture.render(&InitOptions { asynchronous: true, join_stages: true, ..InitOptions::default() });
+    assert_in_order(&code, &["\tcrate::b().await;", "let mut stage", "crate::a().await;", "crate::c().await;", "join_all(stage).await;"]);
+    assert_eq!(code.matches("join_all").count(), 1, "{}", code);
+}
+
+#[test]
+fn dependencies_split_threaded_stage() {
+    let fixture = Fixture::new(&[("src/lib.rs", r#"
+        #[init(stage = 2, before = "crate::y")]
+        fn x() {}
+        #[init(stage = 2)]
+        fn y() {}
+        #[init(stage = 2)]
+        fn z() {}
+    "#)]);
+    let options = InitOptions { stage_strategy: [(2, Strategy::Threaded)].into(), ..InitOptions::default() };
+    let code = fixture.render(&options);
+    assert_in_order(&code, &["\tcrate::x();", "std::thread::scope", "crate::y();", "crate::z();", "});"]);
+    assert_eq!(code.matches("scope.spawn").count(), 2, "{}", code);
+}
+
+#[test]
+fn dependencies_reorder_within_stage() {
+    let fixture = Fixture::new(&[("src/lib.rs", r#"
+        #[init(stage = 0, after = ["crate::b", "crate::c"])]
+        fn a() {}
+        #[init(stage = 0)]
+        fn b() {}
+        #[init(stage = 0, before = "crate::b")]
+        fn c() {}
+    "#)]);
+    assert_eq!(fixture.calls(&InitOptions::default()), ["crate::c", "crate::b", "crate::a"]);
+}
+
+#[test]
+fn dependency_cycle_is_reported() {
+    let fixture = Fixture::new(&[("src/lib.rs", r#"
+        #[init(stage = 0, after = "crate::b")]
+        fn a() {}
+        #[init(stage = 0, after = "crate::c")]
+        fn b() {}
+        #[init(stage = 0, after = "crate::a")]
+        fn c() {}
+    "#)]);
+    match fixture.error(&InitOptions::default()) {
+        InitError::DependencyCycle(cycle) => assert_eq!(cycle, ["crate::a", "crate::c", "crate::b", "crate::a"]),
+        error => panic!("unexpected error: {}", error),
+    }
+}
+
+#[test]
+fn generators_write_separate_items() {
+    let _env = env_lock();
+    let fixture = Fixture::new(&[("src/lib.rs", r#"
+        #[init(stage = 0)]
+        fn core() {}
+        #[deinit(stage = 0)]
+        fn core_stop() {}
+        #[plugin_init(stage = 0)]
+        fn plugin() {}
+        #[plugin_deinit(stage = 0)]
+        fn plugin_stop() {}
+    "#)]);
+    let options = InitOptions { out_dir: Some(PathBuf::from(fixture.path("out"))), explain: true, ..InitOptions::default() };
+    std::fs::create_dir(fixture.path("out")).unwrap();
+    Generator::new().options(options.clone()).source_dir(fixture.src()).run().unwrap();
+    Generator::new().options(options).source_dir(fixture.src())
+        .attribute("plugin_init")
+        .output_file("plugins.rs")
+        .function_name("init_plugins")
+        .run()
+        .unwrap();
+    let read = |file: &str| std::fs::read_to_string(fixture.path(&format!("out/{}", file))).unwrap();
+    let core = read("init.rs");
+    assert!(core.contains("pub fn generated_init() {\n\tcrate::core();\n}\npub fn generated_shutdown() {\n\tcrate::core_stop();\n}"), "{}", core);
+    assert!(!core.contains("plugin"), "{}", core);
+    let plugins = read("plugins.rs");
+    assert!(plugins.contains("pub fn init_plugins() {\n\tcrate::plugin();\n}\npub fn init_plugins_shutdown() {\n\tcrate::plugin_stop();\n}"), "{}", plugins);
+    assert!(read("init_explain.txt").contains("\ncrate::core\n") && !read("init_explain.txt").contains("crate::plugin"));
+    assert!(read("plugins_explain.txt").contains("\ncrate::plugin\n") && !read("plugins_explain.txt").contains("crate::core"));
+}
+
+#[test]
+fn module_cfgs_combine_declaration_and_inner_cfgs() {
+    let fixture = Fixture::new(&[
+        ("src/lib.rs", "#[cfg(feature = \"x\")]\nmod a;\n"),
+        ("src/a.rs", "#![cfg(unix)]\n#[init(stage = 0)]\nfn init() {}\n"),
+    ]);
+    let code = fixture.render(&InitOptions::default());
+    assert!(code.contains("\t#[cfg(all(unix, feature = \"x\"))]\n\tcrate::a::init();"), "{}", code);
+}
+
+/// Writes the files of the ordering tests, in reverse with `reverse`, and includes `generated/extra.rs` from `src/z.rs`.
+fn ordering_fixture(reverse: bool) -> Fixture {
+    let fixture = Fixture::new(&[]);
+    let mut files = vec![
+        ("generated/extra.rs", "#[init(stage = 0)]\nfn inc() {}\n".to_string()),
+        ("src/lib.rs", "mod a;\nmod z;\n#[init(stage = 0)]\nfn m() {}\n#[init(stage = 0)]\nfn b() {}\n".to_string()),
+        ("src/a.rs", "mod b;\n".to_string()),
+        ("src/a/b.rs", "#[init(stage = 0)]\nfn z() {}\n#[init(stage = -1)]\nfn early() {}\n".to_string()),
+        ("src/z.rs", format!("#[init(stage = 0)]\nfn a() {{}}\ninclude!({:?});\n", fixture.path("generated/extra.rs"))),
+    ];
+    if reverse {
+        files.reverse();
+    }
+    for (path, content) in files {
+        fixture.write(path, &content);
+    }
+    fixture
+}
+
+#[test]
+fn same_stage_is_ordered_by_module_and_name() {
+    let fixture = ordering_fixture(false);
+    let expected = ["crate::a::b::early", "crate::b", "crate::m", "crate::a::b::z", "crate::z::a", "crate::z::inc"];
+    assert_eq!(fixture.calls(&InitOptions::default()), expected);
+    let options = InitOptions { follow_module_tree: true, ..InitOptions::default() };
+    assert_eq!(fixture.calls(&options), expected);
+}
+
+#[test]
+fn discovery_order_keeps_included_functions_with_includer() {
+    let fixture = ordering_fixture(false);
+    let options = InitOptions { tie_break: TieBreak::Discovery, ..InitOptions::default() };
+    assert_eq!(fixture.calls(&options), ["crate::a::b::early", "crate::a::b::z", "crate::m", "crate::b", "crate::z::a", "crate::z::inc"]);
+}
+
+#[test]
+fn generated_code_is_identical_across_runs() {
+    let _env = env_lock();
+    let outputs: Vec<String> = [false, true, false].into_iter()
+        .map(|reverse| {
+            let fixture = ordering_fixture(reverse);
+            let out_dir = fixture.path("out");
+            std::fs::create_dir(&out_dir).unwrap();
+            try_generate_init_function_with(&fixture.src(), &InitOptions { out_dir: Some(out_dir.into()), ..InitOptions::default() }).unwrap();
+            std::fs::read_to_string(fixture.path("out/init.rs")).unwrap()
+        })
+        .collect();
+    assert_eq!(outputs[0], outputs[1]);
+    assert_eq!(outputs[0], outputs[2]);
+}
+
+#[test]
+fn stage_names_keep_spaces() {
+    let fixture = Fixture::new(&[("src/lib.rs", r#"
+        #[init(stage = "http server")]
+        fn serve() {}
+        #[init(stage = if cfg!(unix) { "logging" } else { "http server" })]
+        fn log() {}
+        #[deinit(stage = "http server")]
+        fn stop() {}
+    "#)]);
+    let options = InitOptions { stage_names: vec!["logging".to_string(), "http server".to_string()], ..InitOptions::default() };
+    let code = fixture.render(&options);
+    assert_in_order(&code, &["#[cfg(unix)]", "crate::log();", "crate::serve();", "#[cfg(not(unix))]", "crate::log();"]);
+    assert_in_order(&code, &["pub enum InitStage {", "\tLogging = 0,", "\tHttpServer = 1,"]);
+    assert!(code.contains("pub fn generated_shutdown() {\n\tcrate::stop();"), "{}", code);
+}
+
+#[test]
+fn parse_error_reports_file_and_location() {
+    let fixture = Fixture::new(&[("src/lib.rs", "fn a() {}\nstruct;\n")]);
+    let error = fixture.error(&InitOptions::default());
+    match &error {
+        InitError::Parse { path, error } => {
+            assert_eq!(path, Path::new(&fixture.path("src/lib.rs")));
+            assert_eq!((error.span().start().line, error.span().start().column), (2, 6));
+        }
+        error => panic!("unexpected error {:?}", error),
+    }
+    assert!(error.to_string().starts_with(&format!("{}:2:7: failed to parse file", fixture.path("src/lib.rs"))), "{}", error);
+}
+
+#[test]
+fn compile_errors_report_source_errors_only() {
+    let fixture = Fixture::new(&[("src/lib.rs", "#[init(stage = \"late\")]\nfn a() {}\n")]);
+    let out_dir = fixture.path("out");
+    std::fs::create_dir(&out_dir).unwrap();
+    let options = InitOptions { compile_errors: true, out_dir: Some(out_dir.into()), ..InitOptions::default() };
+    try_generate_init_function_with(&fixture.src(), &options).unwrap();
+    let code = std::fs::read_to_string(fixture.path("out/init.rs")).unwrap();
+    assert!(code.contains("compile_error!(\"rs-init: "), "{}", code);
+
+    std::fs::remove_file(fixture.path("out/init.rs")).unwrap();
+    fixture.write("src/lib.rs", "#[init(stage = 0)]\nfn a() {}\n");
+    let options = InitOptions { strict_module_tree: true, ..options };
+    assert!(matches!(try_generate_init_function_with(&fixture.src(), &options), Err(InitError::InvalidOptions(_))));
+    assert!(!Path::new(&fixture.path("out/init.rs")).exists());
+}